@@ -0,0 +1,165 @@
+use crate::chat::FeedId;
+use crate::config;
+use crate::discovery::PeerAddr;
+use crate::peers::PeerSource;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const ADDRESS_BOOK_FILE: &str = "known_peers.toml";
+/// How often the known addresses of offline peers are probed
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Snafu, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum AddressBookError {
+    #[snafu(display("Failed to read address book {}: {}", path.display(), source))]
+    ReadFailed { path: PathBuf, source: io::Error },
+    #[snafu(display("Invalid address book {}: {}", path.display(), source))]
+    ParseFailed {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[snafu(display("Failed to serialize address book: {}", source))]
+    SerializeFailed { source: toml::ser::Error },
+    #[snafu(display("Failed to write address book {}: {}", path.display(), source))]
+    WriteFailed { path: PathBuf, source: io::Error },
+}
+
+/// Where and how we last completed a handshake with a peer
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KnownPeer {
+    /// Multiserver address the peer can be dialed on
+    pub address: String,
+    pub network: String,
+    pub source: PeerSource,
+    /// Unix time of the handshake
+    pub last_seen: u64,
+}
+
+impl KnownPeer {
+    pub fn addr(&self) -> Option<PeerAddr> {
+        self.address.parse().ok()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Entries {
+    peers: BTreeMap<FeedId, KnownPeer>,
+}
+
+/// Every peer we've completed a handshake with, kept next to the config
+/// file so they're listed, and probed, after a restart.
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    /// `None` keeps the address book in memory only
+    path: Option<PathBuf>,
+    entries: Entries,
+}
+
+impl AddressBook {
+    /// `$XDG_CONFIG_HOME/scuttle-chat/known_peers.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        config::config_dir().map(|dir| dir.join(ADDRESS_BOOK_FILE))
+    }
+
+    /// A missing file is an empty address book, created on the first
+    /// handshake.
+    pub fn load(path: PathBuf) -> Result<AddressBook, AddressBookError> {
+        let entries = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                toml::from_str(&contents).context(ParseFailed { path: path.clone() })?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Entries::default(),
+            Err(e) => return Err(e).context(ReadFailed { path }),
+        };
+
+        Ok(AddressBook {
+            path: Some(path),
+            entries,
+        })
+    }
+
+    pub fn contains(&self, feed_id: &str) -> bool {
+        self.entries.peers.contains_key(feed_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&FeedId, &KnownPeer)> {
+        self.entries.peers.iter()
+    }
+
+    /// Records a completed handshake, `addr` being the address to dial the
+    /// peer on next time
+    pub fn handshaked(
+        &mut self,
+        addr: PeerAddr,
+        network: &str,
+        source: PeerSource,
+    ) -> Result<(), AddressBookError> {
+        let last_seen = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+
+        self.entries.peers.insert(
+            addr.feed_id(),
+            KnownPeer {
+                address: addr.to_string(),
+                network: network.to_string(),
+                source,
+                last_seen,
+            },
+        );
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), AddressBookError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let contents = toml::to_string(&self.entries).context(SerializeFailed)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context(WriteFailed { path: path.clone() })?;
+        }
+        fs::write(path, contents).context(WriteFailed { path: path.clone() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::Protocol;
+    use ssb_crypto::PublicKey;
+
+    #[test]
+    fn handshaked_peers_are_there_after_a_reload() {
+        let path = std::env::temp_dir().join(format!("known_peers-{}.toml", std::process::id()));
+        let addr = PeerAddr {
+            protocol: Protocol::Net,
+            socket_addr: ([10, 0, 0, 1], 8008).into(),
+            public_key: PublicKey::from_slice(&[1; 32]).unwrap(),
+        };
+
+        let mut address_book = AddressBook::load(path.clone()).unwrap();
+        assert!(!address_book.contains(&addr.feed_id()));
+        address_book
+            .handshaked(addr, "main", PeerSource::Manual)
+            .unwrap();
+
+        let reloaded = AddressBook::load(path.clone()).unwrap();
+        let _ = fs::remove_file(&path);
+        let (feed_id, known) = reloaded.iter().next().unwrap();
+        assert_eq!(feed_id, &addr.feed_id());
+        assert_eq!(known.address, addr.to_string());
+        assert_eq!(known.network, "main");
+        assert_eq!(known.source, PeerSource::Manual);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::chat::FeedId;
+use crate::config;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+const ADMISSION_FILE: &str = "admission.toml";
+
+#[derive(Snafu, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum AdmissionError {
+    #[snafu(display("Failed to read admission policy {}: {}", path.display(), source))]
+    ReadFailed { path: PathBuf, source: io::Error },
+    #[snafu(display("Invalid admission policy {}: {}", path.display(), source))]
+    ParseFailed {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[snafu(display("Failed to serialize admission policy: {}", source))]
+    SerializeFailed { source: toml::ser::Error },
+    #[snafu(display("Failed to write admission policy {}: {}", path.display(), source))]
+    WriteFailed { path: PathBuf, source: io::Error },
+}
+
+/// How chats started by other peers are let in
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AdmissionMode {
+    /// Every inbound chat opens right away
+    #[default]
+    Open,
+    /// Peers that aren't allowed yet have to be accepted by the user
+    Ask,
+}
+
+/// What to do with an inbound connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Admit,
+    Ask,
+    Refuse,
+}
+
+/// The user's answer to an incoming chat request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    Reject,
+    AlwaysAllow,
+    Block,
+}
+
+impl Decision {
+    pub fn admits(self) -> bool {
+        match self {
+            Decision::Accept | Decision::AlwaysAllow => true,
+            Decision::Reject | Decision::Block => false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Lists {
+    allow: BTreeSet<FeedId>,
+    block: BTreeSet<FeedId>,
+}
+
+/// Peers the user always allows or blocks, kept next to the config file
+/// so the answers outlive the app.
+#[derive(Debug, Default)]
+pub struct AdmissionPolicy {
+    /// `None` keeps the policy in memory only
+    path: Option<PathBuf>,
+    lists: Lists,
+}
+
+impl AdmissionPolicy {
+    /// `$XDG_CONFIG_HOME/scuttle-chat/admission.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        config::config_dir().map(|dir| dir.join(ADMISSION_FILE))
+    }
+
+    /// A missing file is an empty policy, created on the first remembered
+    /// decision.
+    pub fn load(path: PathBuf) -> Result<AdmissionPolicy, AdmissionError> {
+        let lists = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                toml::from_str(&contents).context(ParseFailed { path: path.clone() })?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Lists::default(),
+            Err(e) => return Err(e).context(ReadFailed { path }),
+        };
+
+        Ok(AdmissionPolicy {
+            path: Some(path),
+            lists,
+        })
+    }
+
+    pub fn is_blocked(&self, feed_id: &str) -> bool {
+        self.lists.block.contains(feed_id)
+    }
+
+    pub fn verdict(&self, feed_id: &str, mode: AdmissionMode) -> Verdict {
+        if self.is_blocked(feed_id) {
+            Verdict::Refuse
+        } else if mode == AdmissionMode::Open || self.lists.allow.contains(feed_id) {
+            Verdict::Admit
+        } else {
+            Verdict::Ask
+        }
+    }
+
+    /// Remembers "always allow" and "block" answers, one-off answers leave
+    /// the policy as it is
+    pub fn decide(&mut self, feed_id: &str, decision: Decision) -> Result<(), AdmissionError> {
+        match decision {
+            Decision::AlwaysAllow => {
+                self.lists.block.remove(feed_id);
+                self.lists.allow.insert(feed_id.to_string());
+            }
+            Decision::Block => {
+                self.lists.allow.remove(feed_id);
+                self.lists.block.insert(feed_id.to_string());
+            }
+            Decision::Accept | Decision::Reject => return Ok(()),
+        }
+
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), AdmissionError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let contents = toml::to_string(&self.lists).context(SerializeFailed)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context(WriteFailed { path: path.clone() })?;
+        }
+        fs::write(path, contents).context(WriteFailed { path: path.clone() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "@alice.ed25519";
+    const BOB: &str = "@bob.ed25519";
+
+    #[test]
+    fn ask_mode_asks_about_peers_not_on_a_list() {
+        let policy = AdmissionPolicy::default();
+        assert_eq!(policy.verdict(ALICE, AdmissionMode::Open), Verdict::Admit);
+        assert_eq!(policy.verdict(ALICE, AdmissionMode::Ask), Verdict::Ask);
+    }
+
+    #[test]
+    fn remembered_answers_decide_later_verdicts() {
+        let mut policy = AdmissionPolicy::default();
+        policy.decide(ALICE, Decision::AlwaysAllow).unwrap();
+        policy.decide(BOB, Decision::Block).unwrap();
+
+        assert_eq!(policy.verdict(ALICE, AdmissionMode::Ask), Verdict::Admit);
+        // blocking wins even when every chat is let in
+        assert_eq!(policy.verdict(BOB, AdmissionMode::Open), Verdict::Refuse);
+
+        policy.decide(BOB, Decision::AlwaysAllow).unwrap();
+        assert_eq!(policy.verdict(BOB, AdmissionMode::Ask), Verdict::Admit);
+        assert!(!policy.lists.block.contains(BOB));
+    }
+
+    #[test]
+    fn one_off_answers_are_forgotten() {
+        let mut policy = AdmissionPolicy::default();
+        policy.decide(ALICE, Decision::Accept).unwrap();
+        policy.decide(BOB, Decision::Reject).unwrap();
+
+        assert_eq!(policy.verdict(ALICE, AdmissionMode::Ask), Verdict::Ask);
+        assert_eq!(policy.verdict(BOB, AdmissionMode::Ask), Verdict::Ask);
+    }
+
+    #[test]
+    fn remembered_answers_outlive_the_policy() {
+        let path = std::env::temp_dir()
+            .join(format!("scuttle-chat-test-{}", std::process::id()))
+            .join(ADMISSION_FILE);
+
+        let mut policy = AdmissionPolicy::load(path.clone()).unwrap();
+        policy.decide(ALICE, Decision::AlwaysAllow).unwrap();
+        policy.decide(BOB, Decision::Block).unwrap();
+
+        let reloaded = AdmissionPolicy::load(path.clone()).unwrap();
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        assert_eq!(reloaded.verdict(ALICE, AdmissionMode::Ask), Verdict::Admit);
+        assert_eq!(reloaded.verdict(BOB, AdmissionMode::Open), Verdict::Refuse);
+    }
+}
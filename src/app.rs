@@ -1,16 +1,25 @@
+use crate::address_book::{AddressBook, PROBE_INTERVAL};
+use crate::admission::{AdmissionPolicy, Decision, Verdict};
+use crate::bootstrap::Bootstrap;
 use crate::chat::{ChatMsg, ChatSender, FeedId, PeerChat};
-use crate::discovery::PeerAddr;
+use crate::config::{Config, ConfigWatcher, DiscoveryBackend, UnverifiedAnnouncements};
+use crate::discovery::{Discovery, DiscoveryService, Mode, PeerAddr};
 use crate::event::{Event, Events};
-use crate::peer_manager::{PeerEvent, PeerManager, PeerManagerEvent};
+use crate::mdns::MdnsDiscovery;
+use crate::peer_connection::{Direction, PeerConnection};
+use crate::peer_exchange::{self, PEER_EXCHANGE_INTERVAL};
+use crate::peer_manager::{PeerEvent, PeerManager, PeerManagerEvent, Registration};
+use crate::peers::{PeerList, PeerSource};
 use crate::ssb::SsbConfig;
+use crate::tor::{self, OnionService};
 use crate::ui::draw;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::sync::mpsc;
-use std::sync::Arc;
+use std::time::Instant;
 use termion::event::{Event as TermionEvent, Key, MouseButton, MouseEvent};
 use tui::backend::Backend;
-use tui::style::{Color, Style};
+use tui::style::Style;
 use tui::Terminal;
 
 #[derive(PartialEq, Eq)]
@@ -32,177 +41,763 @@ pub struct UiStyles {
 
 pub struct App<'a> {
     pub mode: AppMode,
-    pub available_peers: HashMap<FeedId, Arc<PeerAddr>>,
-    pub selected: Option<usize>,
+    pub peers: PeerList,
+    /// Whether keys go to the peer filter prompt
+    pub filtering: bool,
+    /// What's typed into the command prompt, while it's open
+    pub command: Option<String>,
     pub peer_chats: HashMap<FeedId, PeerChat>,
     pub debug_log: Vec<(String, &'a str)>,
     pub ui_styles: UiStyles,
     pub events: Events,
     pub peer_manager: PeerManager,
+    pub config: Config,
+    config_watcher: Option<ConfigWatcher>,
+    admission: AdmissionPolicy,
+    /// Inbound connections waiting for the user to accept them, oldest
+    /// first
+    pub pending_admissions: VecDeque<PeerConnection>,
+    address_book: AddressBook,
+    /// When the known addresses of offline peers are probed next
+    next_probe: Instant,
+    last_peer_exchange: Instant,
+    _onion_service: Option<OnionService>,
 }
 
 impl<'a> App<'a> {
-    pub fn new() -> App<'a> {
+    pub fn new() -> Result<App<'a>, Box<dyn Error>> {
+        let mut debug_log = Vec::new();
+
+        let mut config_watcher = ConfigWatcher::default_path().map(ConfigWatcher::new);
+        let config = match config_watcher.as_mut().map(ConfigWatcher::load) {
+            Some(Ok(config)) => config,
+            Some(Err(e)) => {
+                debug_log.push((format!("{}", e), "ERROR"));
+                Config::default()
+            }
+            None => {
+                debug_log.push((
+                    "Cannot find config directory, using defaults".to_string(),
+                    "ERROR",
+                ));
+                Config::default()
+            }
+        };
+
+        // a policy file we can't read is left alone rather than overwritten
+        let admission = match AdmissionPolicy::default_path().map(AdmissionPolicy::load) {
+            Some(Ok(admission)) => admission,
+            Some(Err(e)) => {
+                debug_log.push((format!("{}", e), "ERROR"));
+                AdmissionPolicy::default()
+            }
+            None => AdmissionPolicy::default(),
+        };
+
+        let address_book = match AddressBook::default_path().map(AddressBook::load) {
+            Some(Ok(address_book)) => address_book,
+            Some(Err(e)) => {
+                debug_log.push((format!("{}", e), "ERROR"));
+                AddressBook::default()
+            }
+            None => AddressBook::default(),
+        };
+
         let (pm_tx, pm_rx) = mpsc::channel::<PeerManagerEvent>();
 
         let ssb_config = SsbConfig::default();
 
         let (pk, sk) = ssb_config.keys();
 
-        let peer_manager = PeerManager::new(pk.clone(), sk.clone(), pm_tx);
+        let peer_manager = PeerManager::new(*pk, sk.clone(), pm_tx, &config.network);
 
-        let event_listener = Events::new(pk.clone(), pm_rx);
+        // Tor drops the onion service with the control connection, so
+        // it's held for as long as we run
+        let onion_service = config.network.tor_control.and_then(|control| {
+            match tor::publish(
+                control,
+                config.network.tor_control_password.as_deref(),
+                tor::default_key_path(),
+                config.network.listener_port,
+            ) {
+                Ok(service) => {
+                    debug_log.push((format!("Reachable at {}", service.peer_addr(*pk)), "INFO"));
+                    Some(service)
+                }
+                Err(e) => {
+                    debug_log.push((format!("{}", e), "ERROR"));
+                    None
+                }
+            }
+        });
 
-        let ui_styles = UiStyles {
-            normal_block_style: Style::default().fg(Color::Cyan),
-            hidden_block_style: Style::default().fg(Color::DarkGray),
-            highlighted_block_style: Style::default().fg(Color::LightGreen),
-            info_style: Style::default().fg(Color::Gray),
-            warning_style: Style::default().fg(Color::Yellow),
-            error_style: Style::default().fg(Color::Blue),
-            critical_style: Style::default().fg(Color::Red),
-        };
+        let mut discoveries: Vec<Box<dyn Discovery>> = Vec::new();
+        for backend in config.network.discovery.iter() {
+            match backend {
+                DiscoveryBackend::Broadcast => discoveries.push(Box::new(DiscoveryService::new(
+                    Mode::Debug,
+                    *pk,
+                    sk.clone(),
+                    &config.network,
+                )?)),
+                DiscoveryBackend::Mdns => discoveries.push(Box::new(MdnsDiscovery::new(
+                    Mode::Debug,
+                    *pk,
+                    &config.network,
+                )?)),
+            }
+        }
+
+        if !config.network.bootstrap.is_empty() {
+            discoveries.push(Box::new(Bootstrap::new(*pk, &config.network)));
+        }
+
+        for address in config.network.rooms.iter() {
+            discoveries.push(Box::new(
+                peer_manager.join_room(address.clone(), config.network.networks[0].name.clone()),
+            ));
+        }
+
+        let event_listener = Events::with_config(discoveries, pm_rx, config.event_config());
 
-        App {
+        let ui_styles = config.theme.ui_styles();
+
+        let mut peers = PeerList::new(config.peers.clone());
+        for (_, known) in address_book.iter() {
+            if let Some(addr) = known.addr() {
+                peers.known(addr, known.network.clone(), known.source);
+            }
+        }
+
+        Ok(App {
             mode: AppMode::Normal,
-            available_peers: HashMap::new(),
+            peers,
+            filtering: false,
+            command: None,
             peer_chats: HashMap::new(),
-            selected: None,
-            debug_log: Vec::new(),
+            debug_log,
             ui_styles,
             events: event_listener,
             peer_manager,
-        }
+            config,
+            config_watcher,
+            admission,
+            pending_admissions: VecDeque::new(),
+            address_book,
+            next_probe: Instant::now(),
+            last_peer_exchange: Instant::now(),
+            _onion_service: onion_service,
+        })
     }
 
     pub fn selected_chat(&self) -> Option<&PeerChat> {
-        match self.selected {
-            Some(selected_idx) => {
-                let feed_id = self.peer_list()[selected_idx].clone();
+        self.peers
+            .selected()
+            .and_then(|feed_id| self.peer_chats.get(feed_id))
+    }
 
-                self.peer_chats.get(&feed_id)
+    fn selected_chat_mut(&mut self) -> Option<&mut PeerChat> {
+        let feed_id = self.peers.selected()?;
+        self.peer_chats.get_mut(feed_id)
+    }
+
+    /// Drops peers we haven't heard from in a while, or marks them offline
+    /// if we have a chat with them or they're in the address book.
+    /// Bootstrap peers are announced as often as beacons, so they expire
+    /// the same way once their address stops resolving.
+    fn expire_peers(&mut self) {
+        let timeout = match self.config.discovery.peer_timeout() {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        // exchanged peers are only refreshed with every peer exchange
+        let exchange_timeout = timeout.max(PEER_EXCHANGE_INTERVAL * 2);
+
+        let expired = self
+            .peers
+            .iter()
+            .filter(|(feed_id, peer)| {
+                peer.online
+                    && peer.source != PeerSource::Manual
+                    && peer.last_seen.elapsed()
+                        > match peer.source {
+                            PeerSource::Exchange => exchange_timeout,
+                            _ => timeout,
+                        }
+                    && !self.peer_manager.is_connected(feed_id)
+            })
+            .map(|(feed_id, _)| feed_id.clone())
+            .collect::<Vec<FeedId>>();
+        if expired.is_empty() {
+            return;
+        }
+
+        for feed_id in expired {
+            if self.peer_chats.contains_key(&feed_id) || self.address_book.contains(&feed_id) {
+                if let Some(peer) = self.peers.get_mut(&feed_id) {
+                    peer.online = false;
+                }
+            } else {
+                self.peers.remove(&feed_id);
             }
-            None => None,
+            self.log((format!("{} went offline", feed_id), "INFO"));
         }
     }
 
-    fn selected_chat_mut(&mut self) -> Option<&mut PeerChat> {
-        match self.selected {
-            Some(selected_idx) => {
-                let feed_id = self.peer_list()[selected_idx].clone();
+    fn log(&mut self, entry: (String, &'a str)) {
+        while !self.debug_log.is_empty()
+            && self.debug_log.len() >= self.config.history.debug_log_lines
+        {
+            self.debug_log.remove(0);
+        }
+        self.debug_log.push(entry);
+    }
 
-                self.peer_chats.get_mut(&feed_id)
+    /// Picks up config file changes that can be applied without a restart
+    fn reload_config(&mut self) {
+        let reloaded = match self.config_watcher.as_mut().and_then(ConfigWatcher::poll) {
+            Some(reloaded) => reloaded,
+            None => return,
+        };
+
+        match reloaded {
+            Ok(config) => {
+                if config.network != self.config.network || config.events != self.config.events {
+                    self.log((
+                        "Network and tick rate changes take effect after a restart".to_string(),
+                        "INFO",
+                    ));
+                }
+                self.ui_styles = config.theme.ui_styles();
+                self.config.theme = config.theme;
+                self.config.keybindings = config.keybindings;
+                self.config.history = config.history;
+                self.config.notifications = config.notifications;
+                self.config.admission = config.admission;
+                self.config.discovery = config.discovery;
+                self.peers.set_config(config.peers.clone());
+                self.config.peers = config.peers;
+                self.log(("Reloaded config".to_string(), "INFO"));
             }
-            None => None,
+            Err(e) => self.log((format!("{}", e), "ERROR")),
         }
     }
 
-    pub fn peer_list(&self) -> Vec<&String> {
-        self.available_peers.keys().collect()
+    fn prune_history(&mut self) {
+        let max_messages = self.config.history.max_messages;
+        if max_messages == 0 {
+            return;
+        }
+        for chat in self.peer_chats.values_mut() {
+            if chat.messages.len() > max_messages {
+                let excess = chat.messages.len() - max_messages;
+                chat.messages.drain(..excess);
+            }
+        }
     }
 
-    fn log(&mut self, entry: (String, &'a str)) {
-        if self.debug_log.len() > 15 {
-            self.debug_log.remove(0);
+    /// Dials the last known addresses of offline peers in the address book
+    /// every `PROBE_INTERVAL`, so they come back online even when their
+    /// announcements can't reach us. The probes run concurrently, and the
+    /// ones that complete a handshake arrive like any dialed connection.
+    fn probe_known_peers(&mut self) {
+        if Instant::now() < self.next_probe {
+            return;
+        }
+        self.next_probe = Instant::now() + PROBE_INTERVAL;
+
+        for (feed_id, known) in self.address_book.iter() {
+            let online = self.peers.get(feed_id).is_some_and(|peer| peer.online);
+            let on_network = self
+                .config
+                .network
+                .networks
+                .iter()
+                .any(|n| n.name == known.network);
+            if online || !on_network || self.peer_manager.is_connected(feed_id) {
+                continue;
+            }
+            if let Some(addr) = known.addr() {
+                self.peer_manager.probe(addr, known.network.clone());
+            }
         }
-        self.debug_log.push(entry);
     }
-    fn handle_input(&mut self, input: TermionEvent) -> Result<(), Box<dyn Error>> {
-        match &self.mode {
-            AppMode::Debug => match input {
-                TermionEvent::Key(key) => match key {
-                    Key::Esc => {
-                        self.mode = AppMode::Normal;
+
+    /// Dials a peer in the background, the outcome arrives as a
+    /// `PeerManagerEvent`
+    fn connect(&mut self, feed_id: &str) {
+        match self.peers.get(feed_id) {
+            Some(peer) => {
+                self.peer_manager
+                    .init_connection(peer.dial_addr(), peer.network.clone());
+            }
+            None => self.log((
+                format!(
+                    "No known address for {}, cannot initialize handshake",
+                    feed_id
+                ),
+                "ERROR",
+            )),
+        }
+    }
+
+    /// Lists a peer from a multiserver address the user gave and dials it.
+    /// The peer is on our first network unless `network` names another.
+    pub fn connect_manually(&mut self, address: &str, network: Option<&str>) -> Option<FeedId> {
+        let addr = match PeerAddr::lookup(address) {
+            Ok(addr) => addr,
+            Err(e) => {
+                self.log((format!("Cannot connect to {}: {}", address, e), "ERROR"));
+                return None;
+            }
+        };
+        let network = match network {
+            Some(name) if self.config.network.networks.iter().any(|n| n.name == name) => {
+                name.to_string()
+            }
+            Some(name) => {
+                self.log((
+                    format!(
+                        "Cannot connect to {}: no network named \"{}\"",
+                        address, name
+                    ),
+                    "ERROR",
+                ));
+                return None;
+            }
+            None => self.config.network.networks[0].name.clone(),
+        };
+
+        let feed_id = addr.feed_id();
+        self.peers.added_manually(addr, network);
+        self.log((format!("Connecting to {}", addr), "INFO"));
+        self.connect(&feed_id);
+        Some(feed_id)
+    }
+
+    /// Runs a command typed into the command prompt, `connect <address>
+    /// [network]` is the only one so far
+    fn run_command(&mut self, line: &str) {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("connect"), Some(address), network) => {
+                if let Some(feed_id) = self.connect_manually(address, network) {
+                    self.peers.select(Some(feed_id.clone()));
+                    self.mode = AppMode::Chat(feed_id);
+                }
+            }
+            (Some("connect"), None, _) => {
+                self.log((
+                    "Usage: connect <multiserver address> [network]".to_string(),
+                    "ERROR",
+                ));
+            }
+            (Some(command), _, _) => {
+                self.log((format!("Unknown command: {}", command), "ERROR"));
+            }
+            (None, _, _) => {}
+        }
+    }
+
+    /// Tells every connected peer about the peers we see on its network,
+    /// minus the peer itself and the ones we've blocked
+    fn exchange_peers(&mut self) {
+        self.last_peer_exchange = Instant::now();
+        if !self.config.discovery.peer_exchange {
+            return;
+        }
+
+        for connection in self.peer_manager.connections() {
+            let peers = self
+                .peers
+                .shareable(&connection.network)
+                .into_iter()
+                .filter(|addr| addr.public_key != connection.peer.public_key)
+                .filter(|addr| !self.admission.is_blocked(&addr.feed_id()))
+                .collect::<Vec<PeerAddr>>();
+            if peers.is_empty() {
+                continue;
+            }
+            // a failed send shows up as the connection closing
+            let _ = connection.send(peer_exchange::encode(&peers));
+        }
+    }
+
+    /// Opens a chat over a handshaked connection
+    fn admit(&mut self, peer_connection: PeerConnection) {
+        let peer = peer_connection.peer;
+        let msgs = vec![
+            ChatMsg {
+                sender: ChatSender::Info,
+                message: "Succeeded in handshake!".to_string(),
+            },
+            ChatMsg {
+                sender: ChatSender::Info,
+                message: format!(
+                    "Now connected to {} via encrypted BoxStream ({} network)",
+                    peer.feed_id(),
+                    peer_connection.network
+                ),
+            },
+        ];
+
+        // an open chat supersedes a request still waiting for an answer
+        self.pending_admissions
+            .retain(|pending| pending.peer.feed_id() != peer.feed_id());
+
+        // peers that dialed us without us hearing their
+        // announcement still belong in the peer list
+        self.peers.connected(&peer_connection);
+
+        if let Some(known) = self.peers.get(&peer.feed_id()) {
+            let (addr, network, source) = (known.dial_addr(), known.network.clone(), known.source);
+            if let Err(e) = self.address_book.handshaked(addr, &network, source) {
+                self.log((format!("{}", e), "ERROR"));
+            }
+        }
+
+        match self.peer_manager.register(peer_connection) {
+            Registration::New => {}
+            Registration::Replaced => {
+                self.log((format!("Replaced connection to {}", peer.feed_id()), "INFO"));
+            }
+            Registration::Duplicate => {
+                self.log((
+                    format!("Closed duplicate connection to {}", peer.feed_id()),
+                    "INFO",
+                ));
+                return;
+            }
+        }
+
+        match self.peer_chats.get_mut(&peer.feed_id()) {
+            Some(chat) => {
+                chat.messages.extend(msgs);
+            }
+            None => {
+                self.peer_chats.insert(
+                    peer.feed_id(),
+                    PeerChat {
+                        messages: msgs,
+                        input: "".to_string(),
+                        scroll_offset: 0,
+                    },
+                );
+            }
+        };
+    }
+
+    /// Answers the oldest incoming chat request
+    fn answer_admission(&mut self, decision: Decision) {
+        let peer_connection = match self.pending_admissions.pop_front() {
+            Some(peer_connection) => peer_connection,
+            None => return,
+        };
+        let feed_id = peer_connection.peer.feed_id();
+
+        if let Err(e) = self.admission.decide(&feed_id, decision) {
+            self.log((format!("{}", e), "ERROR"));
+        }
+
+        if decision.admits() {
+            self.admit(peer_connection);
+        } else {
+            // dropping the connection closes it
+            self.log((format!("Rejected chat from {}", feed_id), "INFO"));
+        }
+    }
+
+    fn handle_peer_manager_event(&mut self, pm_event: PeerManagerEvent) {
+        match pm_event {
+            PeerManagerEvent { peer: None, event } => {
+                // inbound handshakes that failed before the client
+                // identified itself, nothing to show but the error
+                match event {
+                    PeerEvent::HandshakeFailed(err) => self.log((format!("{}", err), "ERROR")),
+                    PeerEvent::RoomLeft(address, err) => {
+                        self.log((format!("Lost room {}: {}", address, err), "ERROR"))
                     }
                     _ => {}
-                },
-                _ => {}
+                }
+            }
+            PeerManagerEvent {
+                peer: Some(peer),
+                event,
+            } => match event {
+                PeerEvent::HandshakeSuccessful(peer_connection) => {
+                    if peer_connection.direction == Direction::Inbound {
+                        match self
+                            .admission
+                            .verdict(&peer.feed_id(), self.config.admission.mode)
+                        {
+                            Verdict::Admit => {}
+                            Verdict::Refuse => {
+                                self.log((
+                                    format!("Refused chat from blocked peer {}", peer.feed_id()),
+                                    "INFO",
+                                ));
+                                return;
+                            }
+                            Verdict::Ask => {
+                                // a peer reconnecting while the user makes up
+                                // their mind replaces its earlier request
+                                self.pending_admissions
+                                    .retain(|pending| pending.peer.feed_id() != peer.feed_id());
+                                self.pending_admissions.push_back(*peer_connection);
+                                return;
+                            }
+                        }
+                    }
+                    self.admit(*peer_connection);
+                }
+                PeerEvent::HandshakeFailed(err) => {
+                    self.log((format!("Failed to connect to {}", &peer.feed_id()), "ERROR"));
+                    if self.peer_manager.is_connected(&peer.feed_id()) {
+                        // e.g. our dial lost against the peer dialing us
+                        return;
+                    }
+                    let msgs = vec![
+                        ChatMsg {
+                            sender: ChatSender::Info,
+                            message: format!("Failed to connect to {}", peer.feed_id()),
+                        },
+                        ChatMsg {
+                            sender: ChatSender::Info,
+                            message: format!("{}", err),
+                        },
+                    ];
+                    match self.peer_chats.get_mut(&peer.feed_id()) {
+                        Some(chat) => {
+                            chat.messages.extend(msgs);
+                        }
+                        None => {
+                            self.peer_chats.insert(
+                                peer.feed_id(),
+                                PeerChat {
+                                    messages: msgs,
+                                    input: "".to_string(),
+                                    scroll_offset: 0,
+                                },
+                            );
+                        }
+                    };
+                }
+                PeerEvent::PeersExchanged(addrs) => {
+                    if !self.config.discovery.peer_exchange
+                        || self.config.discovery.unverified == UnverifiedAnnouncements::Drop
+                    {
+                        return;
+                    }
+                    let (network, src) = match self.peer_manager.connection(&peer.feed_id()) {
+                        Some(connection) => {
+                            (connection.network.clone(), connection.peer.socket_addr)
+                        }
+                        None => return,
+                    };
+                    for addr in addrs {
+                        let feed_id = addr.feed_id();
+                        // the peer sees us too
+                        if addr.public_key == self.peer_manager.public_key()
+                            || self.admission.is_blocked(&feed_id)
+                        {
+                            continue;
+                        }
+                        // link-local peers are on the link we reach the
+                        // exchanging peer through
+                        let addr = addr.scoped_to(src);
+                        self.peers.exchanged(addr, network.clone(), &peer.feed_id());
+                    }
+                }
+                PeerEvent::MessageReceived(peer_msg) => {
+                    if let Err(e) = self
+                        .config
+                        .notifications
+                        .message_received(&peer.feed_id(), &peer_msg)
+                    {
+                        self.log((format!("Notification hook failed: {}", e), "ERROR"));
+                    }
+                    let chat_open = self.mode == AppMode::Chat(peer.feed_id());
+                    if let Some(listed) = self.peers.get_mut(&peer.feed_id()) {
+                        listed.last_activity = Some(Instant::now());
+                        if !chat_open {
+                            listed.unread += 1;
+                        }
+                    }
+                    if let Some(chat) = self.peer_chats.get_mut(&peer.feed_id()) {
+                        chat.messages.push(ChatMsg {
+                            sender: ChatSender::Peer(peer.feed_id()),
+                            message: peer_msg,
+                        });
+                    }
+                }
+                PeerEvent::RoomJoined => {
+                    self.log((format!("Joined room {}", peer), "INFO"));
+                }
+                PeerEvent::RoomLeft(..) => {}
+                PeerEvent::ConnectionClosed(connection_id, reason) => {
+                    if !self
+                        .peer_manager
+                        .connection_closed(&peer.feed_id(), connection_id)
+                    {
+                        // a duplicate or replaced connection winding down
+                        return;
+                    }
+                    if let Err(e) = &reason {
+                        self.log((format!("Connection Closed –– Error ({})", e), "ERROR"));
+                    }
+                    if let Some(chat) = self.peer_chats.get_mut(&peer.feed_id()) {
+                        chat.messages.push(ChatMsg {
+                            sender: ChatSender::Info,
+                            message: match reason {
+                                Ok(()) => "Connection Closed -- Goodbye!".to_string(),
+                                Err(e) => format!("Connection Closed –– Error ({})", e),
+                            },
+                        });
+                    }
+                }
             },
+        }
+    }
+
+    fn handle_filter_input(&mut self, key: Key) {
+        let keys = self.config.keybindings;
+        let mut filter = self.peers.filter().to_string();
+
+        match key {
+            Key::Char('\n') => {
+                self.filtering = false;
+                return;
+            }
+            key if key == keys.back => {
+                filter.clear();
+                self.filtering = false;
+            }
+            Key::Up => {
+                self.peers.select_prev();
+                return;
+            }
+            Key::Down => {
+                self.peers.select_next();
+                return;
+            }
+            Key::Backspace => {
+                filter.pop();
+            }
+            Key::Char(c) => filter.push(c),
+            _ => return,
+        }
+
+        self.peers.set_filter(filter);
+    }
+
+    fn handle_command_input(&mut self, key: Key) {
+        let keys = self.config.keybindings;
+        let command = match self.command.as_mut() {
+            Some(command) => command,
+            None => return,
+        };
+
+        match key {
+            Key::Char('\n') => {
+                if let Some(line) = self.command.take() {
+                    self.run_command(&line);
+                }
+            }
+            key if key == keys.back => self.command = None,
+            Key::Backspace => {
+                command.pop();
+            }
+            Key::Char(c) => command.push(c),
+            _ => {}
+        }
+    }
+
+    fn handle_input(&mut self, input: TermionEvent) -> Result<(), Box<dyn Error>> {
+        let keys = self.config.keybindings;
+
+        // the chat request prompt takes all input until it's answered
+        if !self.pending_admissions.is_empty() {
+            if let TermionEvent::Key(Key::Char(c)) = input {
+                match c {
+                    'y' => self.answer_admission(Decision::Accept),
+                    'n' => self.answer_admission(Decision::Reject),
+                    'a' => self.answer_admission(Decision::AlwaysAllow),
+                    'b' => self.answer_admission(Decision::Block),
+                    _ => {}
+                }
+            }
+            return Ok(());
+        }
+
+        // so do the command prompt and the peer filter prompt while they're
+        // open
+        if self.command.is_some() {
+            if let TermionEvent::Key(key) = input {
+                self.handle_command_input(key);
+            }
+            return Ok(());
+        }
+        if self.filtering {
+            if let TermionEvent::Key(key) = input {
+                self.handle_filter_input(key);
+            }
+            return Ok(());
+        }
+
+        match &self.mode {
+            AppMode::Debug => {
+                if let TermionEvent::Key(key) = input {
+                    match key {
+                        key if key == keys.back => {
+                            self.mode = AppMode::Normal;
+                        }
+                        _ => {}
+                    }
+                }
+            }
             AppMode::Normal => {
-                match input {
-                    TermionEvent::Key(key) => match key {
-                        Key::Char('q') => {
+                if let TermionEvent::Key(key) = input {
+                    match key {
+                        key if key == keys.quit => {
                             // should quit the program gracefully!
                             unimplemented!();
                         }
-                        Key::Char('d') => {
+                        key if key == keys.debug => {
                             self.mode = AppMode::Debug;
                         }
                         Key::Left => {
-                            self.selected = None;
-                        }
-                        Key::Esc => {
-                            self.selected = None;
+                            self.peers.select(None);
                         }
-                        Key::Char('j') => {
-                            self.selected = if let Some(selected) = self.selected {
-                                if selected >= self.available_peers.len() - 1 {
-                                    Some(0)
-                                } else {
-                                    Some(selected + 1)
-                                }
-                            } else if !self.available_peers.is_empty() {
-                                Some(0)
+                        key if key == keys.back => {
+                            if self.peers.filter().is_empty() {
+                                self.peers.select(None);
                             } else {
-                                None
+                                self.peers.set_filter(String::new());
                             }
                         }
-                        Key::Char('k') => {
-                            self.selected = if let Some(selected) = self.selected {
-                                if selected > 0 {
-                                    Some(selected - 1)
-                                } else {
-                                    Some(self.available_peers.len() - 1)
-                                }
-                            } else if !self.available_peers.is_empty() {
-                                Some(0)
-                            } else {
-                                None
-                            }
+                        key if key == keys.filter => {
+                            self.filtering = true;
                         }
-                        Key::Down => {
-                            self.selected = if let Some(selected) = self.selected {
-                                if selected >= self.available_peers.len() - 1 {
-                                    Some(0)
-                                } else {
-                                    Some(selected + 1)
-                                }
-                            } else if !self.available_peers.is_empty() {
-                                Some(0)
-                            } else {
-                                None
-                            }
+                        key if key == keys.command => {
+                            self.command = Some(String::new());
                         }
-                        Key::Up => {
-                            self.selected = if let Some(selected) = self.selected {
-                                if selected > 0 {
-                                    Some(selected - 1)
-                                } else {
-                                    Some(self.available_peers.len() - 1)
+                        key if key == keys.down => self.peers.select_next(),
+                        key if key == keys.up => self.peers.select_prev(),
+                        Key::Down => self.peers.select_next(),
+                        Key::Up => self.peers.select_prev(),
+                        key if key == keys.select => {
+                            if let Some(feed_id) = self.peers.selected().cloned() {
+                                if let Some(peer) = self.peers.get_mut(&feed_id) {
+                                    peer.unread = 0;
                                 }
-                            } else if !self.available_peers.is_empty() {
-                                Some(0)
-                            } else {
-                                None
-                            }
-                        }
-                        Key::Char('\n') => {
-                            if let Some(selected) = self.selected {
-                                let feed_id = self.peer_list()[selected].clone();
-                                let ssb_peer = self.available_peers.get(&feed_id).unwrap();
 
-                                match self.peer_chats.get_mut(&feed_id) {
-                                    Some(_peer_chat) => {
-                                        self.mode = AppMode::Chat(feed_id);
-                                    }
-                                    None => {
-                                        // No peer_chat initiated, so we should handshake,
-                                        // which on "success" will initialiae a peer_chat
-                                        // struct
-                                        self.peer_manager.init_connection(**ssb_peer);
-                                        self.mode = AppMode::Chat(feed_id);
-                                    }
-                                };
+                                if !self.peer_chats.contains_key(&feed_id) {
+                                    // No peer_chat initiated, so we should handshake,
+                                    // which on "success" will initialiae a peer_chat
+                                    // struct
+                                    self.connect(&feed_id);
+                                }
+                                self.mode = AppMode::Chat(feed_id);
 
                             // implement something later to poll errors from join handles
                             // this is the only way we'll be able to handle TCP timeouts
@@ -215,20 +810,19 @@ impl<'a> App<'a> {
                             }
                         }
                         _ => {}
-                    },
-                    _ => {}
+                    }
                 }
             }
             AppMode::Chat(feed_id) => {
                 match input {
                     TermionEvent::Mouse(input) => match input {
                         MouseEvent::Press(MouseButton::WheelUp, _, _) => {
-                            if let Some(mut chat) = self.selected_chat_mut() {
+                            if let Some(chat) = self.selected_chat_mut() {
                                 chat.scroll_offset += 1;
                             }
                         }
                         MouseEvent::Press(MouseButton::WheelDown, _, _) => {
-                            if let Some(mut chat) = self.selected_chat_mut() {
+                            if let Some(chat) = self.selected_chat_mut() {
                                 if chat.scroll_offset > 0 {
                                     chat.scroll_offset -= 1;
                                 }
@@ -238,30 +832,34 @@ impl<'a> App<'a> {
                     },
                     TermionEvent::Key(key) => match key {
                         Key::Char('\n') => {
-                            let ssb_peer = self.available_peers.get(feed_id).unwrap();
-
-                            match self.peer_chats.get_mut(feed_id) {
-                                Some(peer_chat) => match &peer_chat.peer_tx {
-                                    Some(tx) => {
-                                        tx.send(peer_chat.input.clone()).unwrap();
-                                        peer_chat.messages.push(ChatMsg {
-                                            sender: ChatSender::_You,
-                                            message: peer_chat.input.clone(),
-                                        });
-                                        peer_chat.input = "".to_string();
-                                    }
-                                    None => {
-                                        peer_chat.messages.push(ChatMsg {
-                                            sender: ChatSender::Info,
-                                            message: "Cannot send message, broken connetion?"
-                                                .to_string(),
-                                        });
+                            let feed_id = feed_id.clone();
+
+                            match self.peer_chats.get_mut(&feed_id) {
+                                Some(peer_chat) => {
+                                    match self.peer_manager.send(&feed_id, peer_chat.input.clone())
+                                    {
+                                        Ok(()) => {
+                                            if let Some(peer) = self.peers.get_mut(&feed_id) {
+                                                peer.last_activity = Some(Instant::now());
+                                            }
+                                            peer_chat.messages.push(ChatMsg {
+                                                sender: ChatSender::_You,
+                                                message: peer_chat.input.clone(),
+                                            });
+                                            peer_chat.input = "".to_string();
+                                        }
+                                        Err(e) => {
+                                            peer_chat.messages.push(ChatMsg {
+                                                sender: ChatSender::Info,
+                                                message: format!("Cannot send message ({})", e),
+                                            });
+                                        }
                                     }
-                                },
+                                }
                                 None => {
                                     // if the chat is selected, but connection is closed,
                                     // initiation a new handshake
-                                    self.peer_manager.init_connection(**ssb_peer);
+                                    self.connect(&feed_id);
                                 }
                             };
                         }
@@ -275,7 +873,7 @@ impl<'a> App<'a> {
                                 chat.input.pop();
                             }
                         }
-                        Key::Esc => {
+                        key if key == keys.back => {
                             self.mode = AppMode::Normal;
                         }
                         _ => {}
@@ -287,134 +885,43 @@ impl<'a> App<'a> {
         Ok(())
     }
 
-    pub fn run<B: Backend>(
-        &mut self,
-        mut terminal: &mut Terminal<B>,
-    ) -> Result<(), Box<dyn Error>> {
+    pub fn run<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<(), Box<dyn Error>> {
         self.peer_manager.start_listener()?;
 
         loop {
-            draw(&mut terminal, &self)?;
+            draw(terminal, self)?;
             match self.events.next()? {
                 Event::Input(input) => self.handle_input(input)?,
                 Event::Tick => {
-                    //let peer_str = "found da peer";
-                    //self.advance();
-                }
-                Event::NewPeer(ssb_peer) => {
-                    let peer_str = format!("{}", ssb_peer);
-                    self.available_peers
-                        .insert(ssb_peer.feed_id(), Arc::new(ssb_peer));
-                    self.log((peer_str, "ANN"));
-                }
-                Event::PeerManagerEvent(pm_event) => match pm_event.event {
-                    PeerEvent::HandshakeSuccessful(peer_connection) => {
-                        let msgs = vec![
-                            ChatMsg {
-                                sender: ChatSender::Info,
-                                message: "Succeeded in handshake!".to_string(),
-                            },
-                            ChatMsg {
-                                sender: ChatSender::Info,
-                                message: format!(
-                                    "Now connected to {} via encrypted BoxStream",
-                                    pm_event.peer.feed_id()
-                                ),
-                            },
-                        ];
-
-                        let peer_writer = peer_connection.peer_writer_tx.clone();
-
-                        match self.peer_chats.get_mut(&pm_event.peer.feed_id()) {
-                            // should check if peer_tx is already set, and handle
-                            // gracefully (fail to set new handshake connection, or
-                            // check prior peer_tx to see if it still is valid)
-                            Some(chat) => {
-                                chat.messages.extend(msgs);
-                                chat.peer_tx = Some(peer_writer);
-                            }
-                            None => {
-                                self.peer_chats.insert(
-                                    pm_event.peer.feed_id(),
-                                    PeerChat {
-                                        messages: msgs,
-                                        input: "".to_string(),
-                                        peer_tx: Some(peer_writer),
-                                        scroll_offset: 0,
-                                    },
-                                );
-                            }
-                        };
-                        self.peer_manager.connections.push(peer_connection);
+                    self.reload_config();
+                    self.prune_history();
+                    self.expire_peers();
+                    self.probe_known_peers();
+                    if self.last_peer_exchange.elapsed() >= PEER_EXCHANGE_INTERVAL {
+                        self.exchange_peers();
                     }
-                    PeerEvent::HandshakeFailed(err) => {
-                        self.log((
-                            format!("Failed to connect to {}", &pm_event.peer.feed_id()),
-                            "ERROR",
-                        ));
-                        let msgs = vec![
-                            ChatMsg {
-                                sender: ChatSender::Info,
-                                message: format!(
-                                    "Failed to connect to {}",
-                                    pm_event.peer.feed_id()
-                                ),
-                            },
-                            ChatMsg {
-                                sender: ChatSender::Info,
-                                message: format!(
-                                    "{}",
-                                    err
-                                ),
-                            },
-                        ];
-                        match self.peer_chats.get_mut(&pm_event.peer.feed_id()) {
-                            // should check if peer_tx is already set, and handle
-                            // gracefully (fail to set new handshake connection, or
-                            // check prior peer_tx to see if it still is valid)
-                            Some(chat) => {
-                                chat.messages.extend(msgs);
-                                chat.peer_tx = None;
-                            }
-                            None => {
-                                self.peer_chats.insert(
-                                    pm_event.peer.feed_id(),
-                                    PeerChat {
-                                        messages: msgs,
-                                        input: "".to_string(),
-                                        peer_tx: None,
-                                        scroll_offset: 0,
-                                    },
-                                );
-                            }
-                        };
-                    }
-                    PeerEvent::MessageReceived(peer_msg) => {
-                        if let Some(chat) = self.peer_chats.get_mut(&pm_event.peer.feed_id()) {
-                            chat.messages.push(ChatMsg {
-                                sender: ChatSender::Peer(pm_event.peer.feed_id()),
-                                message: peer_msg,
-                            });
-                        }
+                }
+                Event::NewPeer(announcement) => {
+                    let peer_str = format!("{} ({})", announcement.peer, announcement.network);
+                    if !announcement.verified
+                        && self.config.discovery.unverified == UnverifiedAnnouncements::Drop
+                    {
+                        self.log((format!("Dropped unverified {}", peer_str), "ANN"));
+                        continue;
                     }
-                    PeerEvent::ConnectionClosed(reason) => {
-                        if let Err(e) = &reason {
-                            self.log((format!("Connection Closed –– Error ({})", e), "ERROR"));
-                        }
-                        if let Some(chat) = self.peer_chats.get_mut(&pm_event.peer.feed_id()) {
-                            chat.messages.push(ChatMsg {
-                                sender: ChatSender::Info,
-                                message: match reason {
-                                    Ok(()) => "Connection Closed -- Goodbye!".to_string(),
-                                    Err(e) => format!("Connection Closed –– Error ({})", e),
-                                },
-                            });
-                            chat.peer_tx = None;
+                    let feed_id = announcement.peer.feed_id();
+                    if self.peers.announced(announcement) {
+                        if let Err(e) = self.config.notifications.new_peer(&feed_id, &peer_str) {
+                            self.log((format!("Notification hook failed: {}", e), "ERROR"));
                         }
                     }
-                },
+                    self.log((peer_str, "ANN"));
+                }
+                Event::DiscoveryStopped(err) => {
+                    self.log((format!("Discovery stopped: {}", err), "ERROR"));
+                }
+                Event::PeerManagerEvent(pm_event) => self.handle_peer_manager_event(pm_event),
             }
         }
-        Ok(())
     }
 }
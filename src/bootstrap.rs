@@ -0,0 +1,99 @@
+use crate::config::NetworkConfig;
+use crate::discovery::{
+    AnnouncementKind, Discovery, DiscoveryServiceError, PeerAddr, PeerAnnouncement,
+    ANNOUNCE_INTERVAL,
+};
+use ssb_crypto::PublicKey;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often bootstrap host names are looked up again
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Announces the configured bootstrap addresses, for peers broadcasts
+/// can't reach, e.g. on other VLANs. Host names are looked up on a thread
+/// of their own, so a slow DNS server doesn't hold up the announcements.
+pub struct Bootstrap {
+    lookups: mpsc::Receiver<Vec<PeerAnnouncement>>,
+    /// Peers the last lookup resolved, announced every `ANNOUNCE_INTERVAL`
+    /// like beacons so they don't expire
+    resolved: Vec<PeerAnnouncement>,
+    queued: VecDeque<PeerAnnouncement>,
+    next_announce: Instant,
+    _lookup_handle: thread::JoinHandle<()>,
+}
+
+impl Bootstrap {
+    pub fn new(public_key: PublicKey, config: &NetworkConfig) -> Bootstrap {
+        let addresses = config.bootstrap.clone();
+        // bootstrap peers are on our first network
+        let network = config.networks[0].name.clone();
+
+        let (tx, lookups) = mpsc::channel();
+        let _lookup_handle = thread::spawn(move || loop {
+            let resolved = addresses
+                .iter()
+                .filter_map(|address| PeerAddr::lookup(address).ok())
+                // a config shared by the whole office lists us too
+                .filter(|peer| peer.public_key != public_key)
+                .map(|peer| PeerAnnouncement {
+                    peer,
+                    network: network.clone(),
+                    kind: AnnouncementKind::Bootstrap,
+                    // the user vouches for the address, the handshake
+                    // proves the key
+                    verified: true,
+                })
+                .collect();
+            if tx.send(resolved).is_err() {
+                return;
+            }
+            thread::sleep(LOOKUP_INTERVAL);
+        });
+
+        Bootstrap {
+            lookups,
+            resolved: Vec::new(),
+            queued: VecDeque::new(),
+            next_announce: Instant::now(),
+            _lookup_handle,
+        }
+    }
+}
+
+impl Discovery for Bootstrap {
+    fn recv(&mut self) -> Result<PeerAnnouncement, DiscoveryServiceError> {
+        loop {
+            if let Some(announcement) = self.queued.pop_front() {
+                return Ok(announcement);
+            }
+
+            let now = Instant::now();
+            if now >= self.next_announce {
+                self.queued.extend(self.resolved.iter().cloned());
+                self.next_announce = now + ANNOUNCE_INTERVAL;
+                continue;
+            }
+
+            match self.lookups.recv_timeout(self.next_announce - now) {
+                // peers that moved are announced at their new address
+                // right away
+                Ok(resolved) => {
+                    self.resolved = resolved;
+                    self.next_announce = Instant::now();
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "Bootstrap lookups stopped",
+                    )
+                    .into());
+                }
+            }
+        }
+    }
+}
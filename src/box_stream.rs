@@ -82,7 +82,7 @@ pub struct BoxWriter<W: Write> {
 }
 
 fn seal_header(payload: &mut [u8; 18], nonce: Nonce, key: &secretbox::Key) -> [u8; 34] {
-    let htag = secretbox::seal_detached(&mut payload[..], &nonce, &key);
+    let htag = secretbox::seal_detached(&mut payload[..], &nonce, key);
 
     let mut hbox = [0; 34];
     hbox[..16].copy_from_slice(&htag[..]);
@@ -97,7 +97,7 @@ fn seal(mut body: Vec<u8>, key: &secretbox::Key, noncegen: &mut NonceGen) -> ([u
 
     let mut head_payload = {
         // Overwrites body with ciphertext
-        let btag = secretbox::seal_detached(&mut body, &body_nonce, &key);
+        let btag = secretbox::seal_detached(&mut body, &body_nonce, key);
 
         let mut hp = [0; 18];
         let (sz, tag) = hp.split_at_mut(2);
@@ -1,5 +1,4 @@
 use std::fmt;
-use std::sync::mpsc;
 
 pub type FeedId = String;
 
@@ -7,7 +6,7 @@ pub type FeedId = String;
 pub enum ChatSender {
     _You,
     Info,
-    Peer(FeedId),
+    Peer(#[allow(dead_code)] FeedId),
 }
 
 #[derive(Debug)]
@@ -19,7 +18,6 @@ pub struct ChatMsg {
 pub struct PeerChat {
     pub messages: Vec<ChatMsg>,
     pub input: String,
-    pub peer_tx: Option<mpsc::Sender<String>>,
     pub scroll_offset: u16,
 }
 
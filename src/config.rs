@@ -0,0 +1,807 @@
+use crate::admission::AdmissionMode;
+use crate::app::UiStyles;
+use crate::chat::FeedId;
+use crate::discovery::{self, ANNOUNCE_INTERVAL, PEER_DISCOVERY_PORT};
+use crate::event;
+use crate::network::{network_key_from_base64, Network};
+use crate::peer_manager::HANDSHAKE_LISTENER_PORT;
+use crate::relay::RELAY_PORT;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use snafu::ResultExt;
+use ssb_crypto::NetworkKey;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use termion::event::Key;
+use tui::style::{Color, Style};
+
+const CONFIG_DIR: &str = "scuttle-chat";
+const CONFIG_FILE: &str = "config.toml";
+
+#[derive(Snafu, Debug)]
+pub enum ConfigError {
+    #[snafu(display("Failed to read config file {}: {}", path.display(), source))]
+    ReadFailed { path: PathBuf, source: io::Error },
+    #[snafu(display("Invalid config file {}: {}", path.display(), source))]
+    ParseFailed {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[snafu(display("Invalid config file {}: {}", path.display(), reason))]
+    InvalidSetting { path: PathBuf, reason: &'static str },
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub theme: ThemeConfig,
+    pub keybindings: Keybindings,
+    pub events: EventsConfig,
+    pub history: HistoryConfig,
+    pub notifications: NotificationsConfig,
+    pub admission: AdmissionConfig,
+    pub discovery: DiscoveryConfig,
+    pub peers: PeersConfig,
+    pub relay: RelayConfig,
+}
+
+impl Config {
+    /// Catches settings that parse but can't work
+    fn validate(&self) -> Result<(), &'static str> {
+        // the event loop would spin without waiting between ticks
+        if self.events.tick_rate_ms == 0 {
+            return Err("events.tick_rate_ms must be more than 0");
+        }
+        if self.network.discovery.contains(&DiscoveryBackend::Mdns)
+            && self.discovery.unverified == UnverifiedAnnouncements::Drop
+        {
+            return Err("mDNS announcements aren't signed, so with \"mdns\" in \
+                 network.discovery, discovery.unverified must be \"show\"");
+        }
+        Ok(())
+    }
+
+    pub fn event_config(&self) -> event::Config {
+        event::Config {
+            tick_rate: Duration::from_millis(self.events.tick_rate_ms),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub discovery_port: u16,
+    pub listener_port: u16,
+    /// Also take connections over WebSockets on this port, e.g. from behind
+    /// an HTTP reverse proxy
+    pub websocket_port: Option<u16>,
+    /// Networks we take part in, in order of preference when a peer
+    /// shares several of them with us
+    #[serde(deserialize_with = "deserialize_networks")]
+    pub networks: Vec<NetworkSpec>,
+    /// How we find peers and let them find us
+    pub discovery: Vec<DiscoveryBackend>,
+    /// Also list SSB clients announcing themselves on the standard SSB
+    /// discovery port, they're main net peers that may not run scuttle-chat
+    pub ssb_discovery: bool,
+    /// Who can see our announcements
+    pub announce: AnnounceMode,
+    /// Passphrases shared with friends, stealth announcements are sealed
+    /// with each of them and announcements sealed with any of them are
+    /// recognised
+    pub stealth_secrets: Vec<String>,
+    /// Feeds stealth announcements are sealed for, and recognised from
+    #[serde(deserialize_with = "deserialize_feed_ids")]
+    pub stealth_contacts: Vec<FeedId>,
+    /// Multiserver addresses, host names allowed, of peers to announce
+    /// whenever they're reachable, on the first network
+    #[serde(deserialize_with = "deserialize_multiserver_addresses")]
+    pub bootstrap: Vec<String>,
+    /// SOCKS5 proxy, e.g. Tor's, onion peers are dialed through
+    pub socks_proxy: Option<SocketAddr>,
+    /// Dial every peer through `socks_proxy`, not only onion peers
+    pub proxy_all: bool,
+    /// Multiserver addresses of SSB rooms to join, their attendants are
+    /// listed on the first network and chats with them are tunnelled
+    /// through the room
+    #[serde(deserialize_with = "deserialize_multiserver_addresses")]
+    pub rooms: Vec<String>,
+    /// Try to punch through NATs to room attendants before tunnelling
+    /// through the room, which tells them our public address
+    pub hole_punching: bool,
+    /// Tor control port to publish our listener as an onion service on
+    pub tor_control: Option<SocketAddr>,
+    /// For Tor control ports that use HashedControlPassword, cookie
+    /// authentication is tried otherwise
+    pub tor_control_password: Option<String>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            discovery_port: PEER_DISCOVERY_PORT,
+            listener_port: HANDSHAKE_LISTENER_PORT,
+            websocket_port: None,
+            networks: vec![NetworkSpec {
+                name: "main".to_string(),
+                caps: None,
+            }],
+            discovery: vec![DiscoveryBackend::Broadcast],
+            ssb_discovery: false,
+            announce: AnnounceMode::Public,
+            stealth_secrets: Vec::new(),
+            stealth_contacts: Vec::new(),
+            bootstrap: Vec::new(),
+            socks_proxy: None,
+            proxy_all: false,
+            rooms: Vec::new(),
+            hole_punching: false,
+            tor_control: None,
+            tor_control_password: None,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn networks(&self) -> Vec<Network> {
+        self.networks.iter().map(NetworkSpec::network).collect()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryBackend {
+    /// UDP broadcasts on the discovery port
+    Broadcast,
+    /// A `_scuttle-chat._tcp` multicast DNS service
+    Mdns,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnounceMode {
+    /// Broadcast our feed id for anyone on the LAN to see
+    Public,
+    /// Only announce ourselves in beacons sealed for `stealth_secrets`
+    /// holders and `stealth_contacts`
+    Stealth,
+    /// Never announce, only listen for others
+    Listen,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkSpec {
+    pub name: String,
+    /// Base64 network key shared by a private network, the SSB main net
+    /// key is used when unset
+    #[serde(default)]
+    pub caps: Option<String>,
+}
+
+impl NetworkSpec {
+    fn network_key(&self) -> Option<NetworkKey> {
+        match &self.caps {
+            Some(caps) => network_key_from_base64(caps),
+            None => Some(NetworkKey::SSB_MAIN_NET),
+        }
+    }
+
+    fn network(&self) -> Network {
+        // keys are validated when the config is deserialized
+        Network::new(&self.name, self.network_key().unwrap())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    #[serde(deserialize_with = "deserialize_color")]
+    pub normal_block: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub hidden_block: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub highlighted_block: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub info: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub warning: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub error: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub critical: Color,
+}
+
+impl Default for ThemeConfig {
+    fn default() -> ThemeConfig {
+        ThemeConfig {
+            normal_block: Color::Cyan,
+            hidden_block: Color::DarkGray,
+            highlighted_block: Color::LightGreen,
+            info: Color::Gray,
+            warning: Color::Yellow,
+            error: Color::Blue,
+            critical: Color::Red,
+        }
+    }
+}
+
+impl ThemeConfig {
+    pub fn ui_styles(&self) -> UiStyles {
+        UiStyles {
+            normal_block_style: Style::default().fg(self.normal_block),
+            hidden_block_style: Style::default().fg(self.hidden_block),
+            highlighted_block_style: Style::default().fg(self.highlighted_block),
+            info_style: Style::default().fg(self.info),
+            warning_style: Style::default().fg(self.warning),
+            error_style: Style::default().fg(self.error),
+            critical_style: Style::default().fg(self.critical),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Keybindings {
+    #[serde(deserialize_with = "deserialize_key")]
+    pub quit: Key,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub debug: Key,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub up: Key,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub down: Key,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub select: Key,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub back: Key,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub filter: Key,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub command: Key,
+}
+
+impl Default for Keybindings {
+    fn default() -> Keybindings {
+        Keybindings {
+            quit: Key::Char('q'),
+            debug: Key::Char('d'),
+            up: Key::Char('k'),
+            down: Key::Char('j'),
+            select: Key::Char('\n'),
+            back: Key::Esc,
+            filter: Key::Char('/'),
+            command: Key::Char(':'),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    pub tick_rate_ms: u64,
+}
+
+impl Default for EventsConfig {
+    fn default() -> EventsConfig {
+        EventsConfig {
+            tick_rate_ms: event::Config::default().tick_rate.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Messages kept per chat, 0 keeps everything
+    pub max_messages: usize,
+    pub debug_log_lines: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig {
+            max_messages: 0,
+            debug_log_lines: 16,
+        }
+    }
+}
+
+/// Shell commands run when something happens. Details are passed in
+/// `SCUTTLE_CHAT_*` environment variables rather than interpolated into
+/// the command, so peers can't inject shell syntax.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    pub on_message: Option<String>,
+    pub on_new_peer: Option<String>,
+}
+
+impl NotificationsConfig {
+    pub fn message_received(&self, feed_id: &str, message: &str) -> io::Result<()> {
+        match &self.on_message {
+            Some(cmd) => run_hook(
+                cmd,
+                &[
+                    ("SCUTTLE_CHAT_FEED_ID", feed_id),
+                    ("SCUTTLE_CHAT_MESSAGE", message),
+                ],
+            ),
+            None => Ok(()),
+        }
+    }
+
+    pub fn new_peer(&self, feed_id: &str, addr: &str) -> io::Result<()> {
+        match &self.on_new_peer {
+            Some(cmd) => run_hook(
+                cmd,
+                &[
+                    ("SCUTTLE_CHAT_FEED_ID", feed_id),
+                    ("SCUTTLE_CHAT_ADDRESS", addr),
+                ],
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+fn run_hook(cmd: &str, envs: &[(&str, &str)]) -> io::Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .envs(envs.iter().cloned())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // reap the hook in the background so it can't leave a zombie behind
+    thread::spawn(move || child.wait());
+
+    Ok(())
+}
+
+/// How the peer list is ordered, after favourites and online peers
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortMode {
+    /// By alias, then feed id
+    #[default]
+    Alphabetical,
+    /// Most recent message first
+    Recent,
+    /// Most unread messages first
+    Unread,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeersConfig {
+    pub sort: SortMode,
+    /// Feed ids pinned to the top of the peer list
+    pub favourites: Vec<FeedId>,
+    /// Names to show instead of feed ids
+    pub aliases: HashMap<FeedId, String>,
+    /// Other names peers go by, which the peer list filter matches too
+    pub petnames: HashMap<FeedId, Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Announcements a peer may miss before it's considered offline, 0
+    /// keeps peers listed forever
+    pub missed_announcements: u32,
+    /// What to do with announcements that aren't signed by the feed they
+    /// announce
+    pub unverified: UnverifiedAnnouncements,
+    /// Tell connected peers about the peers we see, and list the peers
+    /// they tell us about
+    pub peer_exchange: bool,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> DiscoveryConfig {
+        DiscoveryConfig {
+            missed_announcements: 5,
+            unverified: UnverifiedAnnouncements::Show,
+            peer_exchange: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnverifiedAnnouncements {
+    /// List the peer, marked as unverified
+    Show,
+    /// Ignore the announcement
+    Drop,
+}
+
+impl DiscoveryConfig {
+    pub fn peer_timeout(&self) -> Option<Duration> {
+        match self.missed_announcements {
+            0 => None,
+            missed => Some(ANNOUNCE_INTERVAL * missed),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdmissionConfig {
+    pub mode: AdmissionMode,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    /// Port `scuttle-chat relay` takes room clients on
+    pub port: u16,
+    /// Feeds allowed to attend the relay, anyone may if it's empty
+    #[serde(deserialize_with = "deserialize_feed_ids")]
+    pub allowed: Vec<FeedId>,
+}
+
+impl Default for RelayConfig {
+    fn default() -> RelayConfig {
+        RelayConfig {
+            port: RELAY_PORT,
+            allowed: Vec::new(),
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/scuttle-chat`
+pub fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(CONFIG_DIR))
+}
+
+/// Loads the config file and notices when it changes on disk.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    last_modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: PathBuf) -> ConfigWatcher {
+        ConfigWatcher {
+            path,
+            last_modified: None,
+        }
+    }
+
+    /// `$XDG_CONFIG_HOME/scuttle-chat/config.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(CONFIG_FILE))
+    }
+
+    /// A missing config file is not an error, it just means defaults.
+    pub fn load(&mut self) -> Result<Config, ConfigError> {
+        self.last_modified = modified(&self.path);
+
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => {
+                return Err(e).context(ReadFailed {
+                    path: self.path.clone(),
+                })
+            }
+        };
+
+        let config: Config = toml::from_str(&contents).context(ParseFailed {
+            path: self.path.clone(),
+        })?;
+        config
+            .validate()
+            .map_err(|reason| ConfigError::InvalidSetting {
+                path: self.path.clone(),
+                reason,
+            })?;
+        Ok(config)
+    }
+
+    /// Reloads the config if the file was modified, created or removed
+    /// since the last load.
+    pub fn poll(&mut self) -> Option<Result<Config, ConfigError>> {
+        if modified(&self.path) != self.last_modified {
+            Some(self.load())
+        } else {
+            None
+        }
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn deserialize_feed_ids<'de, D>(deserializer: D) -> Result<Vec<FeedId>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let feed_ids = Vec::<FeedId>::deserialize(deserializer)?;
+
+    match feed_ids
+        .iter()
+        .find(|feed_id| discovery::parse_feed_id(feed_id).is_none())
+    {
+        Some(feed_id) => Err(de::Error::custom(format!(
+            "\"{}\" is not an @<base64>.ed25519 feed id",
+            feed_id
+        ))),
+        None => Ok(feed_ids),
+    }
+}
+
+fn deserialize_multiserver_addresses<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let addresses = Vec::<String>::deserialize(deserializer)?;
+
+    // host names are only looked up when they're dialed
+    for address in addresses.iter() {
+        if let Err(e) = discovery::parse_multiserver(address) {
+            return Err(de::Error::custom(e));
+        }
+    }
+
+    Ok(addresses)
+}
+
+fn deserialize_networks<'de, D>(deserializer: D) -> Result<Vec<NetworkSpec>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let specs = Vec::<NetworkSpec>::deserialize(deserializer)?;
+
+    if specs.is_empty() {
+        return Err(de::Error::custom("at least one network is required"));
+    }
+
+    let mut names = HashSet::new();
+    let mut ids = HashSet::new();
+    for spec in specs.iter() {
+        let key = spec.network_key().ok_or_else(|| {
+            de::Error::custom(format!(
+                "caps of network \"{}\" must be a base64 encoded 32 byte network key",
+                spec.name
+            ))
+        })?;
+        if !names.insert(spec.name.clone()) {
+            return Err(de::Error::custom(format!(
+                "network \"{}\" is configured twice",
+                spec.name
+            )));
+        }
+        if !ids.insert(Network::new(&spec.name, key).id) {
+            return Err(de::Error::custom(format!(
+                "network \"{}\" reuses the key of another network",
+                spec.name
+            )));
+        }
+    }
+
+    Ok(specs)
+}
+
+fn deserialize_color<'de, D>(deserializer: D) -> Result<Color, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_color(&s).ok_or_else(|| de::Error::custom(format!("unknown color \"{}\"", s)))
+}
+
+fn parse_color(s: &str) -> Option<Color> {
+    let color = match s.to_lowercase().replace(['_', '-'], "").as_str() {
+        "reset" => Color::Reset,
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "darkgray" | "darkgrey" => Color::DarkGray,
+        "lightred" => Color::LightRed,
+        "lightgreen" => Color::LightGreen,
+        "lightyellow" => Color::LightYellow,
+        "lightblue" => Color::LightBlue,
+        "lightmagenta" => Color::LightMagenta,
+        "lightcyan" => Color::LightCyan,
+        "white" => Color::White,
+        hex if hex.starts_with('#') && hex.len() == 7 => {
+            let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+            Color::Rgb(channel(1)?, channel(3)?, channel(5)?)
+        }
+        indexed => Color::Indexed(indexed.parse().ok()?),
+    };
+
+    Some(color)
+}
+
+fn deserialize_key<'de, D>(deserializer: D) -> Result<Key, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_key(&s).ok_or_else(|| de::Error::custom(format!("unknown key \"{}\"", s)))
+}
+
+/// Parses "q", "enter", "esc", "up", "ctrl-c", "alt-x", "f1", ...
+fn parse_key(s: &str) -> Option<Key> {
+    let single_char = |s: &str| {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    };
+
+    if let Some(c) = single_char(s) {
+        return Some(Key::Char(c));
+    }
+
+    // ASCII case folding keeps every byte where it was, so what follows a
+    // prefix of `lower` is at the same offset in `s`
+    let lower = s.to_ascii_lowercase();
+    let after = |prefix| {
+        lower
+            .strip_prefix(prefix)
+            .map(|rest| &s[s.len() - rest.len()..])
+    };
+
+    let key = match lower.as_str() {
+        "enter" | "return" => Key::Char('\n'),
+        "tab" => Key::Char('\t'),
+        "space" => Key::Char(' '),
+        "esc" | "escape" => Key::Esc,
+        "backspace" => Key::Backspace,
+        "delete" => Key::Delete,
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" => Key::PageUp,
+        "pagedown" => Key::PageDown,
+        _ => {
+            if let Some(c) = after("ctrl-") {
+                Key::Ctrl(single_char(c)?)
+            } else if let Some(c) = after("alt-") {
+                Key::Alt(single_char(c)?)
+            } else {
+                Key::F(after("f")?.parse().ok()?)
+            }
+        }
+    };
+
+    Some(key)
+}
+
+/// Inverse of `parse_key`, for showing bindings in the UI
+pub fn key_name(key: Key) -> String {
+    match key {
+        Key::Char('\n') => "RETURN".to_string(),
+        Key::Char('\t') => "TAB".to_string(),
+        Key::Char(' ') => "SPACE".to_string(),
+        Key::Char(c) => c.to_string(),
+        Key::Ctrl(c) => format!("CTRL-{}", c),
+        Key::Alt(c) => format!("ALT-{}", c),
+        Key::F(n) => format!("F{}", n),
+        Key::Esc => "ESC".to_string(),
+        Key::Backspace => "BACKSPACE".to_string(),
+        Key::Delete => "DELETE".to_string(),
+        Key::Up => "UP".to_string(),
+        Key::Down => "DOWN".to_string(),
+        Key::Left => "LEFT".to_string(),
+        Key::Right => "RIGHT".to_string(),
+        Key::Home => "HOME".to_string(),
+        Key::End => "END".to_string(),
+        Key::PageUp => "PAGEUP".to_string(),
+        Key::PageDown => "PAGEDOWN".to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn missing_sections_keep_their_defaults() {
+        let config = parse("[keybindings]\nquit = \"ctrl-c\"\n").unwrap();
+        assert_eq!(config.keybindings.quit, Key::Ctrl('c'));
+        assert_eq!(config.keybindings.up, Keybindings::default().up);
+        assert_eq!(config.network, NetworkConfig::default());
+        assert_eq!(config.theme, ThemeConfig::default());
+    }
+
+    #[test]
+    fn colors_and_keys_are_checked_when_parsing() {
+        let config = parse("[theme]\ninfo = \"light-blue\"\nerror = \"#ff8000\"\n").unwrap();
+        assert_eq!(config.theme.info, Color::LightBlue);
+        assert_eq!(config.theme.error, Color::Rgb(0xff, 0x80, 0));
+
+        assert!(parse("[theme]\ninfo = \"ultraviolet\"\n").is_err());
+        assert!(parse("[keybindings]\nquit = \"ctrl-\"\n").is_err());
+        assert!(parse("[network]\nlistener = 8008\n").is_err());
+    }
+
+    #[test]
+    fn a_zero_tick_rate_is_refused() {
+        let config = parse("[events]\ntick_rate_ms = 0\n").unwrap();
+        assert!(config.validate().is_err());
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn dropping_unverified_peers_is_refused_with_mdns() {
+        let drop = "[discovery]\nunverified = \"drop\"\n";
+        assert!(parse(drop).unwrap().validate().is_ok());
+
+        let mdns = "[network]\ndiscovery = [\"mdns\"]\n";
+        assert!(parse(mdns).unwrap().validate().is_ok());
+        assert!(parse(&format!("{}{}", mdns, drop))
+            .unwrap()
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn keys_parse_whatever_their_case() {
+        assert_eq!(parse_key("Q"), Some(Key::Char('Q')));
+        assert_eq!(parse_key("Enter"), Some(Key::Char('\n')));
+        assert_eq!(parse_key("ESC"), Some(Key::Esc));
+        assert_eq!(parse_key("PageDown"), Some(Key::PageDown));
+        assert_eq!(parse_key("F12"), Some(Key::F(12)));
+        assert_eq!(parse_key("fx"), None);
+        assert_eq!(parse_key("hyper-x"), None);
+    }
+
+    #[test]
+    fn modifier_prefixes_keep_the_case_of_their_key() {
+        assert_eq!(parse_key("CTRL-c"), Some(Key::Ctrl('c')));
+        assert_eq!(parse_key("Alt-X"), Some(Key::Alt('X')));
+        assert_eq!(parse_key("ctrl-é"), Some(Key::Ctrl('é')));
+        // lowercasing "İ" would add a byte before the key
+        assert_eq!(parse_key("ctrl-İ"), Some(Key::Ctrl('İ')));
+        assert_eq!(parse_key("ctrl-ab"), None);
+    }
+
+    #[test]
+    fn key_names_parse_back_to_their_key() {
+        for key in [
+            Key::Char('\n'),
+            Key::Char(' '),
+            Key::Char('j'),
+            Key::Ctrl('c'),
+            Key::Alt('x'),
+            Key::F(5),
+            Key::Esc,
+            Key::PageUp,
+        ]
+        .iter()
+        {
+            assert_eq!(parse_key(&key_name(*key)), Some(*key));
+        }
+    }
+}
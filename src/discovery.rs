@@ -1,20 +1,34 @@
-use base64;
 use net2::unix::UnixUdpBuilderExt;
 use net2::UdpBuilder;
-use nix::sys::socket::{InetAddr, SockAddr};
+use nix::net::if_::{if_nametoindex, InterfaceFlags};
+use nix::sys::socket::SockAddr;
 use regex::Regex;
-use ssb_crypto::PublicKey;
+use sodiumoxide::crypto::sign::{sign_detached, verify_detached, Signature};
+use ssb_crypto::{PublicKey, SecretKey};
+use std::collections::HashMap;
 use std::error;
 use std::fmt;
 use std::io;
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs, UdpSocket};
 use std::str::FromStr;
+use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::peer_manager::HANDSHAKE_LISTENER_PORT;
+use crate::config::{AnnounceMode, NetworkConfig};
+use crate::network::NetworkId;
+use crate::stealth::{StealthKeys, STEALTH_PREFIX};
 
 pub const PEER_DISCOVERY_PORT: u16 = 45982;
+/// Where standard SSB clients announce themselves
+pub const SSB_DISCOVERY_PORT: u16 = 8008;
+/// How often we broadcast our announcement
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+/// How far a beacon's timestamp may be from our clock before it's
+/// considered replayed
+const MAX_BEACON_AGE: Duration = Duration::from_secs(30);
+/// ff02::1, which every IPv6 interface on the link is a member of
+const ALL_NODES_MULTICAST: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
 
 #[derive(Debug, Clone, Copy)]
 pub struct PeerAddr {
@@ -29,16 +43,117 @@ impl fmt::Display for PeerAddr {
         let protocol = match &self.protocol {
             Protocol::WebSocket => "ws://",
             Protocol::Net => "net:",
+            Protocol::Onion(onion) => {
+                return write!(
+                    f,
+                    "onion:{}:{}~shs:{}",
+                    onion.host(),
+                    self.socket_addr.port(),
+                    feed_id
+                )
+            }
+            Protocol::Tunnel(portal) => {
+                return write!(
+                    f,
+                    "tunnel:@{}.ed25519:@{}.ed25519~shs:{}",
+                    base64::encode(&portal.0),
+                    feed_id,
+                    feed_id
+                )
+            }
         };
 
-        write!(f, "{}{}~shs:{}", protocol, &self.socket_addr, feed_id)
+        // link-local addresses keep their "%<scope id>", `parse_socket_addr`
+        // reads it back
+        write!(f, "{}{}~shs:{}", protocol, self.socket_addr, feed_id)
     }
 }
 
+/// A peer heard on one of our networks
+#[derive(Debug, Clone)]
+pub struct PeerAnnouncement {
+    pub peer: PeerAddr,
+    /// Name of the network we share with the peer, the first one in our
+    /// config if it announced several
+    pub network: String,
+    pub kind: AnnouncementKind,
+    /// Whether the announcement was signed by the announced feed, recently
+    /// enough not to be a replay. Unsigned announcements are from older
+    /// clients, SSB clients or someone spoofing the feed.
+    pub verified: bool,
+}
+
+/// Where an announcement came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnouncementKind {
+    /// A scuttle-chat announcement, the address is the peer's chat
+    /// listener
+    Chat,
+    /// A standard SSB announcement, the address is the peer's SSB server
+    /// and the peer may not run scuttle-chat at all
+    Ssb,
+    /// An address from the configured bootstrap list
+    Bootstrap,
+    /// A scuttle-chat announcement sealed for us, the peer only wants
+    /// friends to know it's here
+    Stealth,
+    /// An attendant of a room we're in, reached through the room's tunnel
+    Room,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Protocol {
     WebSocket,
     Net,
+    /// Dialed through the SOCKS5 proxy, the peer's `socket_addr` only
+    /// carries the port
+    Onion(OnionAddr),
+    /// Tunnelled through the room server with this key, the peer's
+    /// `socket_addr` is unused
+    Tunnel(PublicKey),
+}
+
+/// Length of a v3 onion service name, without ".onion"
+const ONION_NAME_LEN: usize = 56;
+
+/// A v3 onion service name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OnionAddr([u8; ONION_NAME_LEN]);
+
+impl OnionAddr {
+    /// Parses "<56 base32 characters>.onion"
+    pub fn parse(host: &str) -> Option<OnionAddr> {
+        let name = host.to_ascii_lowercase();
+        if !name.ends_with(".onion") {
+            return None;
+        }
+        let name = &name.as_bytes()[..name.len() - ".onion".len()];
+        if name.len() != ONION_NAME_LEN
+            || !name
+                .iter()
+                .all(|c| c.is_ascii_lowercase() || (b'2'..=b'7').contains(c))
+        {
+            return None;
+        }
+
+        let mut onion = [0; ONION_NAME_LEN];
+        onion.copy_from_slice(name);
+        Some(OnionAddr(onion))
+    }
+
+    pub fn host(&self) -> String {
+        // only ever holds base32 characters
+        format!("{}.onion", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl Protocol {
+    pub fn is_onion(self) -> bool {
+        match self {
+            Protocol::Onion(_) => true,
+            Protocol::Net | Protocol::WebSocket | Protocol::Tunnel(_) => false,
+        }
+    }
 }
 
 impl PeerAddr {
@@ -46,14 +161,74 @@ impl PeerAddr {
         let encoded_bytes = base64::encode(&self.public_key.0);
         format!("@{}.ed25519", encoded_bytes)
     }
+
+    /// The address as other hosts see it, the scope id of a link-local
+    /// address names one of our interfaces
+    pub fn without_scope(mut self) -> PeerAddr {
+        if let SocketAddr::V6(addr) = &mut self.socket_addr {
+            addr.set_scope_id(0);
+        }
+        self
+    }
+
+    /// Scopes a link-local address that has no scope id to the interface
+    /// `src` reached us through, the only one it's reachable through
+    pub fn scoped_to(mut self, src: SocketAddr) -> PeerAddr {
+        if let (SocketAddr::V6(addr), SocketAddr::V6(src)) = (&mut self.socket_addr, src) {
+            if is_link_local(addr.ip()) && addr.scope_id() == 0 {
+                addr.set_scope_id(src.scope_id());
+            }
+        }
+        self
+    }
+}
+
+/// The public key of an "@<base64>.ed25519" feed id
+pub fn parse_feed_id(feed_id: &str) -> Option<PublicKey> {
+    let encoded = feed_id.trim_start_matches('@').trim_end_matches(".ed25519");
+    PublicKey::from_slice(&base64::decode(encoded).ok()?)
 }
 
 #[derive(Debug)]
-pub struct ParsePeerAddrError();
+pub enum ParsePeerAddrError {
+    /// Not of the form `<protocol>:<address>~shs:<key>`
+    Malformed(String),
+    UnknownProtocol(String),
+    InvalidAddress(String),
+    InvalidKey(String),
+    InvalidUtf8,
+    InvalidNetworkId(String),
+    /// None of the addresses in an announcement could be parsed
+    NoAddress,
+}
 
 impl fmt::Display for ParsePeerAddrError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Failed to parse PeerAddr string")
+        match self {
+            ParsePeerAddrError::Malformed(s) => write!(
+                f,
+                "\"{}\" is not a multiserver address, expected net:<ip>:<port>~shs:<key>",
+                s
+            ),
+            ParsePeerAddrError::UnknownProtocol(protocol) => write!(
+                f,
+                "Unknown protocol \"{}\", expected \"net:\" or \"ws://\"",
+                protocol
+            ),
+            ParsePeerAddrError::InvalidAddress(addr) => {
+                write!(f, "\"{}\" is not a valid host and port", addr)
+            }
+            ParsePeerAddrError::InvalidKey(key) => {
+                write!(f, "\"{}\" is not a base64 encoded ed25519 public key", key)
+            }
+            ParsePeerAddrError::InvalidUtf8 => write!(f, "Announcement is not valid UTF-8"),
+            ParsePeerAddrError::InvalidNetworkId(id) => {
+                write!(f, "Announcement has an invalid network id \"{}\"", id)
+            }
+            ParsePeerAddrError::NoAddress => {
+                write!(f, "Announcement has no address we can connect to")
+            }
+        }
     }
 }
 
@@ -65,24 +240,88 @@ impl error::Error for ParsePeerAddrError {
     }
 }
 
+/// Splits a multiserver address into its protocol, address and key
+pub fn parse_multiserver(s: &str) -> Result<(Protocol, &str, PublicKey), ParsePeerAddrError> {
+    let re = Regex::new(r"^([^:]*:(?://)?)(.*?)~shs:(.*)$").unwrap();
+
+    let groups = re
+        .captures(s)
+        .ok_or_else(|| ParsePeerAddrError::Malformed(s.to_string()))?;
+
+    let addr = groups.get(2).unwrap().as_str();
+    let protocol = match &groups[1] {
+        "ws://" => Protocol::WebSocket,
+        "net:" => Protocol::Net,
+        "onion:" => Protocol::Onion(
+            addr.rfind(':')
+                .and_then(|port_start| OnionAddr::parse(&addr[..port_start]))
+                .ok_or_else(|| ParsePeerAddrError::InvalidAddress(addr.to_string()))?,
+        ),
+        "tunnel:" => Protocol::Tunnel(
+            addr.split(':')
+                .next()
+                .and_then(parse_feed_id)
+                .ok_or_else(|| ParsePeerAddrError::InvalidAddress(addr.to_string()))?,
+        ),
+        other => return Err(ParsePeerAddrError::UnknownProtocol(other.to_string())),
+    };
+
+    let key = &groups[3];
+    let public_key = base64::decode(key)
+        .ok()
+        .and_then(|pk_bytes| PublicKey::from_slice(&pk_bytes))
+        .ok_or_else(|| ParsePeerAddrError::InvalidKey(key.to_string()))?;
+
+    Ok((protocol, addr, public_key))
+}
+
+/// The socket address of a peer, onion peers get only their port and an
+/// unspecified IP, tunnelled peers not even a port
+fn peer_socket_addr(protocol: Protocol, addr: &str) -> Option<SocketAddr> {
+    match protocol {
+        Protocol::Onion(_) => {
+            let port = addr[addr.rfind(':')? + 1..].parse().ok()?;
+            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))
+        }
+        Protocol::Tunnel(_) => Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)),
+        Protocol::Net | Protocol::WebSocket => parse_socket_addr(addr),
+    }
+}
+
 impl FromStr for PeerAddr {
     type Err = ParsePeerAddrError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let re = Regex::new(r"(ws://|net:)(.*?)~shs:(.*)").unwrap();
+        let (protocol, addr, public_key) = parse_multiserver(s)?;
+        let socket_addr = peer_socket_addr(protocol, addr)
+            .ok_or_else(|| ParsePeerAddrError::InvalidAddress(addr.to_string()))?;
 
-        let groups = re.captures(s).ok_or(ParsePeerAddrError())?;
-
-        let protocol = match &groups[1] {
-            "ws://" => Some(Protocol::WebSocket),
-            "net:" => Some(Protocol::Net),
-            _ => None,
-        }
-        .ok_or(ParsePeerAddrError())?;
+        Ok(PeerAddr {
+            protocol,
+            socket_addr,
+            public_key,
+        })
+    }
+}
 
-        let socket_addr: SocketAddr = groups[2].parse().map_err(|_| ParsePeerAddrError())?;
-        let pk_bytes = base64::decode(&groups[3]).map_err(|_| ParsePeerAddrError())?;
-        let public_key = PublicKey::from_slice(&pk_bytes).ok_or(ParsePeerAddrError())?;
+impl PeerAddr {
+    /// Like `from_str`, but also takes host names, which are looked up.
+    /// Only for addresses the user typed, looking up names from
+    /// announcements would let anyone on the LAN make us query DNS.
+    pub fn lookup(s: &str) -> Result<PeerAddr, ParsePeerAddrError> {
+        let (protocol, addr, public_key) = parse_multiserver(s)?;
+        let socket_addr = match peer_socket_addr(protocol, addr) {
+            Some(socket_addr) => socket_addr,
+            // onion names must never reach DNS
+            None if protocol.is_onion() => {
+                return Err(ParsePeerAddrError::InvalidAddress(addr.to_string()))
+            }
+            None => addr
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut socket_addrs| socket_addrs.next())
+                .ok_or_else(|| ParsePeerAddrError::InvalidAddress(addr.to_string()))?,
+        };
 
         Ok(PeerAddr {
             protocol,
@@ -92,21 +331,61 @@ impl FromStr for PeerAddr {
     }
 }
 
+/// Parses "1.2.3.4:8008" and "[fe80::1]:8008", including bracketed IPv6
+/// addresses with a "%eth0" or "%2" zone
+fn parse_socket_addr(s: &str) -> Option<SocketAddr> {
+    if let Ok(socket_addr) = s.parse() {
+        return Some(socket_addr);
+    }
+
+    if !s.starts_with('[') {
+        return None;
+    }
+    let end = s.find("]:")?;
+    let host = &s[1..end];
+    let port = s[end + 2..].parse().ok()?;
+    let (ip, zone) = host.split_at(host.find('%')?);
+    let ip = ip.parse::<Ipv6Addr>().ok()?;
+    let scope_id = match zone[1..].parse::<u32>() {
+        Ok(scope_id) => scope_id,
+        Err(_) => if_nametoindex(&zone[1..]).ok()?,
+    };
+
+    Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+}
+
+fn is_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Turns IPv4 addresses that reached an IPv6 socket as "::ffff:1.2.3.4"
+/// back into plain IPv4 addresses
+pub fn unmap_ipv4(socket_addr: SocketAddr) -> SocketAddr {
+    match socket_addr {
+        SocketAddr::V6(addr) => match addr.ip().segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo))),
+                addr.port(),
+            ),
+            _ => socket_addr,
+        },
+        SocketAddr::V4(_) => socket_addr,
+    }
+}
+
 #[derive(Debug)]
 pub enum DiscoveryServiceError {
-    ParseError(ParsePeerAddrError),
-    GetLocalAddrError,
-    IoError(std::io::Error),
+    Parse(ParsePeerAddrError),
+    Io(std::io::Error),
+    Mdns(mdns_sd::Error),
 }
 
 impl fmt::Display for DiscoveryServiceError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            DiscoveryServiceError::ParseError(e) => fmt::Display::fmt(e, f),
-            DiscoveryServiceError::IoError(e) => fmt::Display::fmt(e, f),
-            DiscoveryServiceError::GetLocalAddrError => {
-                fmt::Display::fmt("Failed to determine local IP Address", f)
-            }
+            DiscoveryServiceError::Parse(e) => fmt::Display::fmt(e, f),
+            DiscoveryServiceError::Io(e) => fmt::Display::fmt(e, f),
+            DiscoveryServiceError::Mdns(e) => fmt::Display::fmt(e, f),
         }
     }
 }
@@ -115,69 +394,345 @@ impl fmt::Display for DiscoveryServiceError {
 impl error::Error for DiscoveryServiceError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
-            DiscoveryServiceError::ParseError(e) => Some(e),
-            DiscoveryServiceError::IoError(e) => Some(e),
-            DiscoveryServiceError::GetLocalAddrError => None,
+            DiscoveryServiceError::Parse(e) => Some(e),
+            DiscoveryServiceError::Io(e) => Some(e),
+            DiscoveryServiceError::Mdns(e) => Some(e),
         }
     }
 }
 
 impl From<io::Error> for DiscoveryServiceError {
     fn from(error: io::Error) -> DiscoveryServiceError {
-        DiscoveryServiceError::IoError(error)
+        DiscoveryServiceError::Io(error)
+    }
+}
+
+impl From<mdns_sd::Error> for DiscoveryServiceError {
+    fn from(error: mdns_sd::Error) -> DiscoveryServiceError {
+        DiscoveryServiceError::Mdns(error)
     }
 }
 
 impl From<ParsePeerAddrError> for DiscoveryServiceError {
     fn from(error: ParsePeerAddrError) -> DiscoveryServiceError {
-        DiscoveryServiceError::ParseError(error)
+        DiscoveryServiceError::Parse(error)
     }
 }
 
+/// A source of peer announcements. `Events` runs every configured
+/// backend on a thread of its own and merges what they find.
+pub trait Discovery: Send {
+    /// Waits for the next announcement from a peer on one of our networks
+    fn recv(&mut self) -> Result<PeerAnnouncement, DiscoveryServiceError>;
+}
+
+/// Name of the first of our networks the peer announced, if any
+pub fn shared_network(
+    networks: &[(NetworkId, String)],
+    network_ids: &[NetworkId],
+) -> Option<String> {
+    networks
+        .iter()
+        .find(|(id, _)| network_ids.contains(id))
+        .map(|(_, name)| name.clone())
+}
+
+type Datagram = (Vec<u8>, SocketAddr, AnnouncementKind);
+
 pub struct DiscoveryService {
-    announce_listener: UdpSocket,
-    _announcer_handle: thread::JoinHandle<Result<(), io::Error>>,
-    ignore_broadcasts_from: Option<SocketAddr>,
+    /// Announcements received on any of our listener sockets
+    announcements: mpsc::Receiver<Datagram>,
+    _listener_handles: Vec<thread::JoinHandle<io::Result<()>>>,
+    /// `None` when listening only
+    _announcer_handle: Option<thread::JoinHandle<Result<(), io::Error>>>,
+    /// Our own public key, whichever interface our announcements arrive on
+    ignore_broadcasts_from: Option<PublicKey>,
+    networks: Vec<(NetworkId, String)>,
+    /// Newest verified timestamp heard from each feed, older signed
+    /// announcements are replays
+    timestamps: HashMap<String, u64>,
+    /// Opens stealth beacons from friends
+    stealth_keys: StealthKeys,
 }
 
-fn get_local_addr() -> Option<SocketAddr> {
-    let addrs = nix::ifaddrs::getifaddrs().ok()?;
+/// Announcements are a `;` separated list of multiserver addresses, plus
+/// our own `caps:<network id>` entries naming the networks the peer is on.
+/// Announcements without any come from main net peers.
+const NETWORK_ID_PREFIX: &str = "caps:";
+/// Signed announcements end in `ts:<unix time>;sig:<signature>`, the
+/// signature covering everything before `;sig:`
+const TIMESTAMP_PREFIX: &str = "ts:";
+const SIGNATURE_PREFIX: &str = ";sig:";
+
+/// A beacon's signed timestamp, if it carries a valid signature by `peer`
+fn signed_timestamp(buf_str: &str, peer: &PeerAddr) -> Option<u64> {
+    let sig_start = buf_str.rfind(SIGNATURE_PREFIX)?;
+    let (signed, sig) = buf_str.split_at(sig_start);
+    let sig = base64::decode(&sig[SIGNATURE_PREFIX.len()..]).ok()?;
+    let sig = Signature::from_slice(&sig)?;
+    if !verify_detached(&sig, signed.as_bytes(), &peer.public_key) {
+        return None;
+    }
+
+    signed
+        .split(';')
+        .rfind(|entry| entry.starts_with(TIMESTAMP_PREFIX))?[TIMESTAMP_PREFIX.len()..]
+        .parse()
+        .ok()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// Parses an announcement that arrived from `src`, picking the best of
+/// the addresses it lists, along with its timestamp if it's signed by the
+/// announced feed. Entries we can't parse are skipped, SSB clients may
+/// list transports we don't speak.
+fn parse_announcement(
+    buf: &[u8],
+    src: SocketAddr,
+) -> Result<(PeerAddr, Vec<NetworkId>, Option<u64>), ParsePeerAddrError> {
+    let buf_str = std::str::from_utf8(buf).map_err(|_| ParsePeerAddrError::InvalidUtf8)?;
+
+    let mut peers = Vec::new();
+    let mut network_ids = Vec::new();
+
+    for entry in buf_str.split(';') {
+        if entry.starts_with(TIMESTAMP_PREFIX) || entry.starts_with(&SIGNATURE_PREFIX[1..]) {
+            continue;
+        } else if let Some(network_id) = entry.strip_prefix(NETWORK_ID_PREFIX) {
+            let network_id = NetworkId::parse(network_id)
+                .ok_or_else(|| ParsePeerAddrError::InvalidNetworkId(network_id.to_string()))?;
+            network_ids.push(network_id);
+        } else if let Ok(peer) = entry.parse::<PeerAddr>() {
+            peers.push(peer);
+        }
+    }
+
+    if network_ids.is_empty() {
+        network_ids.push(NetworkId::main_net());
+    }
+
+    let peer = peers
+        .into_iter()
+        .max_by_key(|peer| address_rank(peer, &src))
+        .ok_or(ParsePeerAddrError::NoAddress)?;
+    let timestamp = signed_timestamp(buf_str, &peer);
+
+    Ok((peer, network_ids, timestamp))
+}
+
+/// Prefers plain TCP over websockets, then the address the announcement
+/// was sent from, then anything over link-local IPv6 addresses, which are
+/// only reachable through one interface
+fn address_rank(peer: &PeerAddr, src: &SocketAddr) -> (bool, bool, bool) {
+    let is_net = match peer.protocol {
+        Protocol::Net => true,
+        Protocol::WebSocket | Protocol::Onion(_) | Protocol::Tunnel(_) => false,
+    };
+    let is_sender = peer.socket_addr.ip() == unmap_ipv4(*src).ip();
+    let is_global = match peer.socket_addr {
+        SocketAddr::V6(addr) => !is_link_local(addr.ip()),
+        SocketAddr::V4(_) => true,
+    };
+
+    (is_net, is_sender, is_global)
+}
+
+/// An interface we announce ourselves on
+struct BroadcastInterface {
+    addr: Ipv4Addr,
+    broadcast: Ipv4Addr,
+}
+
+fn ipv4_addr(sock_addr: Option<SockAddr>) -> Option<Ipv4Addr> {
+    match sock_addr {
+        Some(SockAddr::Inet(inet_addr)) => match inet_addr.to_std() {
+            SocketAddr::V4(addr) => Some(*addr.ip()),
+            SocketAddr::V6(_) => None,
+        },
+        _ => None,
+    }
+}
+
+/// Link-local IPv6 addresses of the interfaces that are up and can
+/// multicast, with the interface's index as scope id
+fn multicast_interfaces() -> Vec<SocketAddrV6> {
+    let addrs = match nix::ifaddrs::getifaddrs() {
+        Ok(addrs) => addrs,
+        Err(_) => return Vec::new(),
+    };
 
     addrs
-        .flat_map(|ifaddr| match ifaddr.address {
-            Some(SockAddr::Inet(address @ InetAddr::V4(_)))
-                if (address.to_str() != "127.0.0.1:0") =>
-            {
-                Some(address.to_std())
-            }
+        .filter(|ifaddr| {
+            ifaddr.flags.contains(InterfaceFlags::IFF_UP)
+                && ifaddr.flags.contains(InterfaceFlags::IFF_MULTICAST)
+                && !ifaddr.flags.contains(InterfaceFlags::IFF_LOOPBACK)
+        })
+        .filter_map(|ifaddr| match ifaddr.address {
+            Some(SockAddr::Inet(inet_addr)) => match inet_addr.to_std() {
+                SocketAddr::V6(addr) if is_link_local(addr.ip()) => Some(addr),
+                _ => None,
+            },
             _ => None,
         })
-        .next()
+        .collect()
 }
 
-fn init_announcer(
+/// IPv4 interfaces that are up and can broadcast, looked up afresh every
+/// time so interfaces that come and go are followed
+fn broadcast_interfaces() -> Vec<BroadcastInterface> {
+    let addrs = match nix::ifaddrs::getifaddrs() {
+        Ok(addrs) => addrs,
+        Err(_) => return Vec::new(),
+    };
+
+    addrs
+        .filter(|ifaddr| {
+            ifaddr.flags.contains(InterfaceFlags::IFF_UP)
+                && ifaddr.flags.contains(InterfaceFlags::IFF_BROADCAST)
+                && !ifaddr.flags.contains(InterfaceFlags::IFF_LOOPBACK)
+        })
+        .filter_map(|ifaddr| {
+            let addr = ipv4_addr(ifaddr.address)?;
+            // fall back to working the directed broadcast out from the
+            // netmask if the interface doesn't report one
+            let broadcast = ipv4_addr(ifaddr.broadcast).or_else(|| {
+                let netmask = u32::from(ipv4_addr(ifaddr.netmask)?);
+                Some(Ipv4Addr::from(u32::from(addr) | !netmask))
+            })?;
+            Some(BroadcastInterface { addr, broadcast })
+        })
+        .collect()
+}
+
+fn announcement(
     socket_addr: SocketAddr,
     public_key: PublicKey,
-) -> thread::JoinHandle<Result<(), io::Error>> {
-    let ann_peer = PeerAddr {
+    secret_key: &SecretKey,
+    network_ids: &[NetworkId],
+) -> String {
+    let mut announcement = PeerAddr {
         protocol: Protocol::Net,
         socket_addr,
         public_key,
     }
     .to_string();
+    for network_id in network_ids {
+        announcement.push_str(&format!(";{}{}", NETWORK_ID_PREFIX, network_id));
+    }
+    announcement.push_str(&format!(";{}{}", TIMESTAMP_PREFIX, unix_time()));
+    let sig = sign_detached(announcement.as_bytes(), secret_key);
+    announcement.push_str(&format!(
+        "{}{}",
+        SIGNATURE_PREFIX,
+        base64::encode(&sig.0[..])
+    ));
+    announcement
+}
 
+/// Sends one announcement per interface, carrying the interface's own
+/// address, to the interface's directed broadcast address, and to the
+/// link-local all nodes group on IPv6 interfaces. With `stealth_keys`,
+/// each announcement goes out sealed once per friend instead.
+fn init_announcer(
+    public_key: PublicKey,
+    secret_key: SecretKey,
+    network_ids: Vec<NetworkId>,
+    discovery_port: u16,
+    listener_port: u16,
+    stealth_keys: Option<StealthKeys>,
+) -> thread::JoinHandle<Result<(), io::Error>> {
     thread::spawn(move || {
         let socket = UdpSocket::bind("0.0.0.0:0")?;
         socket.set_broadcast(true)?;
+        // hosts without IPv6 still announce over IPv4
+        let socket_v6 = UdpSocket::bind("[::]:0").ok();
+        let beacons = |announcement: String| match &stealth_keys {
+            Some(stealth_keys) => stealth_keys.seal(&announcement),
+            None => vec![announcement],
+        };
+
+        loop {
+            for interface in broadcast_interfaces() {
+                let ann_peer = announcement(
+                    SocketAddr::new(IpAddr::V4(interface.addr), listener_port),
+                    public_key,
+                    &secret_key,
+                    &network_ids,
+                );
+
+                // an interface going down between listing and sending
+                // shouldn't stop announcements on the others
+                for beacon in beacons(ann_peer) {
+                    let _ = socket.send_to(
+                        beacon.as_bytes(),
+                        SocketAddr::new(IpAddr::V4(interface.broadcast), discovery_port),
+                    );
+                }
+            }
+            if let Some(socket_v6) = &socket_v6 {
+                for interface in multicast_interfaces() {
+                    let ann_peer = announcement(
+                        SocketAddr::new(IpAddr::V6(*interface.ip()), listener_port),
+                        public_key,
+                        &secret_key,
+                        &network_ids,
+                    );
+
+                    // the scope id picks the interface to send on
+                    for beacon in beacons(ann_peer) {
+                        let _ = socket_v6.send_to(
+                            beacon.as_bytes(),
+                            SocketAddrV6::new(
+                                ALL_NODES_MULTICAST,
+                                discovery_port,
+                                0,
+                                interface.scope_id(),
+                            ),
+                        );
+                    }
+                }
+            }
+            thread::sleep(ANNOUNCE_INTERVAL);
+        }
+    })
+}
 
-        let buf_bytes = ann_peer.as_bytes();
+fn bind_listener_v6(discovery_port: u16) -> io::Result<UdpSocket> {
+    let listener = UdpBuilder::new_v6()?
+        .only_v6(true)?
+        .reuse_port(true)?
+        .bind(SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            discovery_port,
+        ))?;
+
+    // every interface is in the all nodes group already, joining just
+    // makes sure this socket is handed the announcements
+    for interface in multicast_interfaces() {
+        let _ = listener.join_multicast_v6(&ALL_NODES_MULTICAST, interface.scope_id());
+    }
+
+    Ok(listener)
+}
 
+fn spawn_listener(
+    listener: UdpSocket,
+    kind: AnnouncementKind,
+    tx: mpsc::Sender<Datagram>,
+) -> thread::JoinHandle<io::Result<()>> {
+    thread::spawn(move || {
+        let mut buf = [0; 1024];
         loop {
-            socket.send_to(
-                &buf_bytes,
-                format!("255.255.255.255:{}", PEER_DISCOVERY_PORT),
-            )?;
-            thread::sleep(Duration::from_secs_f32(2.0));
+            let (received, src) = listener.recv_from(&mut buf)?;
+            if tx.send((buf[..received].to_vec(), src, kind)).is_err() {
+                return Ok(());
+            }
         }
     })
 }
@@ -189,41 +744,240 @@ pub enum Mode {
 }
 
 impl DiscoveryService {
-    pub fn new(mode: Mode, public_key: PublicKey) -> Result<Self, DiscoveryServiceError> {
-        let socket_addr = format!("0.0.0.0:{}", PEER_DISCOVERY_PORT);
-        let announce_listener = UdpBuilder::new_v4()?.reuse_port(true)?.bind(&socket_addr)?;
+    pub fn new(
+        mode: Mode,
+        public_key: PublicKey,
+        secret_key: SecretKey,
+        config: &NetworkConfig,
+    ) -> Result<Self, DiscoveryServiceError> {
+        let networks = config.networks();
+        let discovery_port = config.discovery_port;
+        let socket_addr = format!("0.0.0.0:{}", discovery_port);
+        let mut listeners = vec![(
+            UdpBuilder::new_v4()?.reuse_port(true)?.bind(&socket_addr)?,
+            AnnouncementKind::Chat,
+        )];
+
+        // hosts without IPv6 still discover peers over IPv4
+        if let Ok(listener_v6) = bind_listener_v6(discovery_port) {
+            listeners.push((listener_v6, AnnouncementKind::Chat));
+        }
 
-        let mut hs_listener_socket_addr =
-            get_local_addr().ok_or(DiscoveryServiceError::GetLocalAddrError)?;
-        hs_listener_socket_addr.set_port(HANDSHAKE_LISTENER_PORT);
+        // shares the port with any SSB client running on this host
+        if config.ssb_discovery {
+            let ssb_socket_addr = format!("0.0.0.0:{}", SSB_DISCOVERY_PORT);
+            listeners.push((
+                UdpBuilder::new_v4()?
+                    .reuse_address(true)?
+                    .reuse_port(true)?
+                    .bind(&ssb_socket_addr)?,
+                AnnouncementKind::Ssb,
+            ));
+        }
 
-        let _announcer_handle = init_announcer(hs_listener_socket_addr, public_key);
+        let (tx, announcements) = mpsc::channel();
+        let _listener_handles = listeners
+            .into_iter()
+            .map(|(listener, kind)| spawn_listener(listener, kind, tx.clone()))
+            .collect();
+
+        let stealth_keys = StealthKeys::new(
+            &secret_key,
+            &config.stealth_secrets,
+            &config.stealth_contacts,
+        );
+        let announcer_stealth_keys = match config.announce {
+            AnnounceMode::Stealth => Some(stealth_keys.clone()),
+            AnnounceMode::Public | AnnounceMode::Listen => None,
+        };
+        let _announcer_handle = if config.announce == AnnounceMode::Listen {
+            None
+        } else {
+            Some(init_announcer(
+                public_key,
+                secret_key,
+                networks.iter().map(|n| n.id).collect(),
+                discovery_port,
+                config.listener_port,
+                announcer_stealth_keys,
+            ))
+        };
 
         Ok(DiscoveryService {
-            announce_listener,
+            announcements,
+            _listener_handles,
             _announcer_handle,
             ignore_broadcasts_from: match mode {
-                Mode::Normal => Some(hs_listener_socket_addr),
+                Mode::Normal => Some(public_key),
                 Mode::Debug => None,
             },
+            networks: networks.iter().map(|n| (n.id, n.name.clone())).collect(),
+            timestamps: HashMap::new(),
+            stealth_keys,
         })
     }
+}
 
-    pub fn recv(&self) -> Result<PeerAddr, DiscoveryServiceError> {
-        let socket = &self.announce_listener;
+/// Whether a signed `timestamp` is close enough to `now` and no older than
+/// the newest one heard from the feed. Beacons may arrive on several
+/// interfaces at once, so a timestamp equal to the newest one isn't a
+/// replay.
+fn fresh_timestamp(
+    timestamps: &mut HashMap<String, u64>,
+    peer: &PeerAddr,
+    timestamp: Option<u64>,
+    now: u64,
+) -> bool {
+    let timestamp = match timestamp {
+        Some(timestamp) => timestamp,
+        None => return false,
+    };
+    let max_age = MAX_BEACON_AGE.as_secs();
+    if timestamp + max_age < now || timestamp > now + max_age {
+        return false;
+    }
 
-        let mut buf = [0; 1024];
-        let received = socket.recv(&mut buf)?;
-        let buf_str = std::str::from_utf8(&buf[..received]).unwrap();
-        let peer = buf_str
-            .split(";")
-            .map(str::parse::<PeerAddr>)
-            .next()
-            .ok_or(ParsePeerAddrError())??;
-
-        match self.ignore_broadcasts_from {
-            Some(socket_addr) if socket_addr == peer.socket_addr => self.recv(),
-            _ => Ok(peer),
+    let newest = timestamps.entry(peer.feed_id()).or_insert(0);
+    if timestamp < *newest {
+        return false;
+    }
+    *newest = timestamp;
+    true
+}
+
+impl Discovery for DiscoveryService {
+    fn recv(&mut self) -> Result<PeerAnnouncement, DiscoveryServiceError> {
+        loop {
+            let (buf, src, kind) = self.announcements.recv().map_err(|_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "Discovery listeners stopped")
+            })?;
+            // stealth beacons we have no key for are meant for others
+            let (buf, kind) = if buf.starts_with(STEALTH_PREFIX.as_bytes()) {
+                let beacon = String::from_utf8_lossy(&buf);
+                match self.stealth_keys.open(&beacon) {
+                    Some(announcement) => (announcement.into_bytes(), AnnouncementKind::Stealth),
+                    None => continue,
+                }
+            } else {
+                (buf, kind)
+            };
+            let (peer, network_ids, timestamp) = parse_announcement(&buf, src)?;
+            let peer = peer.scoped_to(src);
+
+            let network = match shared_network(&self.networks, &network_ids) {
+                Some(network) => network,
+                None => continue,
+            };
+
+            match self.ignore_broadcasts_from {
+                Some(public_key) if public_key == peer.public_key => continue,
+                _ => {
+                    let verified =
+                        fresh_timestamp(&mut self.timestamps, &peer, timestamp, unix_time());
+                    return Ok(PeerAnnouncement {
+                        peer,
+                        network,
+                        kind,
+                        verified,
+                    });
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_local_addresses_keep_their_scope() {
+        let address = format!("net:[fe80::1%3]:8008~shs:{}", base64::encode(&[7; 32]));
+        let peer = address.parse::<PeerAddr>().unwrap();
+        match peer.socket_addr {
+            SocketAddr::V6(addr) => assert_eq!(addr.scope_id(), 3),
+            SocketAddr::V4(_) => panic!("parsed as IPv4"),
+        }
+
+        assert_eq!(peer.to_string(), address);
+
+        let unscoped = peer.without_scope();
+        assert_eq!(
+            unscoped.to_string(),
+            format!("net:[fe80::1]:8008~shs:{}", base64::encode(&[7; 32]))
+        );
+        let src = "[fe80::2%5]:45982".parse().unwrap();
+        assert_eq!(
+            unscoped.scoped_to(src).socket_addr.to_string(),
+            "[fe80::1%5]:8008"
+        );
+        assert_eq!(peer.scoped_to(src).socket_addr, peer.socket_addr);
+    }
+
+    fn keypair() -> (PublicKey, SecretKey) {
+        let (pk, sk) = sodiumoxide::crypto::sign::gen_keypair();
+        (
+            PublicKey::from_slice(&pk.0).unwrap(),
+            SecretKey::from_slice(&sk.0).unwrap(),
+        )
+    }
+
+    fn parse(announcement: &str) -> (PeerAddr, Option<u64>) {
+        let src = ([10, 0, 0, 1], PEER_DISCOVERY_PORT).into();
+        let (peer, _, timestamp) = parse_announcement(announcement.as_bytes(), src).unwrap();
+        (peer, timestamp)
+    }
+
+    #[test]
+    fn signed_announcements_carry_their_timestamp() {
+        let (public_key, secret_key) = keypair();
+        let socket_addr = ([10, 0, 0, 1], 8008).into();
+        let signed = announcement(socket_addr, public_key, &secret_key, &[]);
+
+        let (peer, timestamp) = parse(&signed);
+        assert_eq!(peer.public_key, public_key);
+        let timestamp = timestamp.unwrap();
+        assert!(timestamp.max(unix_time()) - timestamp.min(unix_time()) <= 1);
+
+        let unsigned = &signed[..signed.rfind(SIGNATURE_PREFIX).unwrap()];
+        assert_eq!(parse(unsigned).1, None);
+    }
+
+    #[test]
+    fn announcements_signed_by_another_feed_are_unverified() {
+        let (public_key, _) = keypair();
+        let (_, other_secret_key) = keypair();
+        let socket_addr = ([10, 0, 0, 1], 8008).into();
+        let forged = announcement(socket_addr, public_key, &other_secret_key, &[]);
+        assert_eq!(parse(&forged).1, None);
+
+        let (_, secret_key) = keypair();
+        let signed = announcement(socket_addr, public_key, &secret_key, &[]);
+        let tampered = signed.replacen("10.0.0.1", "10.0.0.2", 1);
+        assert_eq!(parse(&tampered).1, None);
+    }
+
+    #[test]
+    fn stale_and_replayed_timestamps_are_refused() {
+        let (public_key, _) = keypair();
+        let peer = PeerAddr {
+            protocol: Protocol::Net,
+            socket_addr: ([10, 0, 0, 1], 8008).into(),
+            public_key,
+        };
+        let now = 1_000_000;
+        let max_age = MAX_BEACON_AGE.as_secs();
+        let mut timestamps = HashMap::new();
+        let mut fresh = |timestamp, now| fresh_timestamp(&mut timestamps, &peer, timestamp, now);
+
+        assert!(!fresh(None, now));
+        assert!(!fresh(Some(now - max_age - 1), now));
+        assert!(!fresh(Some(now + max_age + 1), now));
+
+        assert!(fresh(Some(now), now));
+        // the same beacon heard on another interface
+        assert!(fresh(Some(now), now));
+        assert!(!fresh(Some(now - 1), now));
+        assert!(fresh(Some(now + 2), now + 2));
+    }
+}
@@ -1,17 +1,18 @@
-use crate::discovery::{DiscoveryService, Mode, PeerAddr};
+use crate::discovery::{Discovery, DiscoveryServiceError, PeerAnnouncement};
 use crate::peer_manager::PeerManagerEvent;
-use ssb_crypto::PublicKey;
 use std::io;
 use std::sync::mpsc;
 use std::thread;
 use std::time::{Duration, Instant};
-use termion::event::{Event as TermionEvent, Key, MouseButton, MouseEvent};
+use termion::event::{Event as TermionEvent, MouseButton, MouseEvent};
 use termion::input::TermRead;
 
 pub enum Event<I> {
     Input(I),
     Tick,
-    NewPeer(PeerAddr),
+    NewPeer(PeerAnnouncement),
+    /// A discovery backend failed for good and hears no more peers
+    DiscoveryStopped(DiscoveryServiceError),
     PeerManagerEvent(PeerManagerEvent),
 }
 
@@ -19,32 +20,36 @@ pub struct Events {
     rx: mpsc::Receiver<Event<TermionEvent>>,
     _input_handle: thread::JoinHandle<()>,
     _tick_handle: thread::JoinHandle<()>,
-    _new_peer_handle: thread::JoinHandle<()>,
+    _new_peer_handles: Vec<thread::JoinHandle<()>>,
     _pm_handle: thread::JoinHandle<()>,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct Config {
-    pub exit_key: Key,
     pub tick_rate: Duration,
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
-            exit_key: Key::Char('q'),
             tick_rate: Duration::from_millis(250),
         }
     }
 }
 
 impl Events {
-    pub fn new(public_key: PublicKey, peer_manager_rx: mpsc::Receiver<PeerManagerEvent>) -> Events {
-        Events::with_config(public_key, peer_manager_rx, Config::default())
+    #[allow(dead_code)]
+    pub fn new(
+        peer_listeners: Vec<Box<dyn Discovery>>,
+        peer_manager_rx: mpsc::Receiver<PeerManagerEvent>,
+    ) -> Events {
+        Events::with_config(peer_listeners, peer_manager_rx, Config::default())
     }
 
+    /// Peers found by any of the `peer_listeners` arrive as
+    /// `Event::NewPeer`
     pub fn with_config(
-        public_key: PublicKey,
+        peer_listeners: Vec<Box<dyn Discovery>>,
         peer_manager_rx: mpsc::Receiver<PeerManagerEvent>,
         config: Config,
     ) -> Events {
@@ -58,7 +63,7 @@ impl Events {
                 let mut throttle_scroll_events = |scroll_evt| {
                     let new_scroll = Instant::now();
                     if new_scroll.duration_since(last_scroll) > Duration::from_millis(20) {
-                        if let Err(_) = tx.send(Event::Input(scroll_evt)) {
+                        if tx.send(Event::Input(scroll_evt)).is_err() {
                             return;
                         }
 
@@ -82,7 +87,7 @@ impl Events {
                             )),
                         ) => throttle_scroll_events(scroll_evt),
                         Ok(key) => {
-                            if let Err(_) = tx.send(Event::Input(key)) {
+                            if tx.send(Event::Input(key)).is_err() {
                                 return;
                             }
                         }
@@ -98,15 +103,28 @@ impl Events {
                 thread::sleep(config.tick_rate);
             })
         };
-        let _new_peer_handle = {
-            let tx = tx.clone();
-            let peer_listener = DiscoveryService::new(Mode::Debug, public_key).unwrap();
-            thread::spawn(move || loop {
-                if let Ok(ssb_peer) = peer_listener.recv() {
-                    let _res = tx.send(Event::NewPeer(ssb_peer));
-                }
+        let _new_peer_handles = peer_listeners
+            .into_iter()
+            .map(|mut peer_listener| {
+                let tx = tx.clone();
+                thread::spawn(move || loop {
+                    match peer_listener.recv() {
+                        Ok(ssb_peer) => {
+                            let _res = tx.send(Event::NewPeer(ssb_peer));
+                        }
+                        // a malformed announcement says nothing about the
+                        // next one
+                        Err(DiscoveryServiceError::Parse(_)) => {}
+                        // the backend's sockets or daemon are gone, asking
+                        // again would only spin
+                        Err(err) => {
+                            let _res = tx.send(Event::DiscoveryStopped(err));
+                            return;
+                        }
+                    }
+                })
             })
-        };
+            .collect();
         let _pm_handle = {
             let tx = tx.clone();
             thread::spawn(move || loop {
@@ -120,7 +138,7 @@ impl Events {
             rx,
             _input_handle,
             _tick_handle,
-            _new_peer_handle,
+            _new_peer_handles,
             _pm_handle,
         }
     }
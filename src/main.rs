@@ -1,5 +1,5 @@
 use std::io;
-use std::panic::PanicInfo;
+use std::panic::PanicHookInfo;
 use termion::input::MouseTerminal;
 use termion::raw::IntoRawMode;
 use termion::screen::AlternateScreen;
@@ -9,19 +9,34 @@ use tui::Terminal;
 #[macro_use]
 extern crate snafu;
 
+mod address_book;
+mod admission;
 mod app;
+mod bootstrap;
 mod box_stream;
 mod chat;
+mod config;
 mod discovery;
 mod event;
+mod mdns;
+mod muxrpc;
+mod network;
 mod peer_manager;
 mod ui;
+mod ws;
 mod peer_connection;
+mod peer_exchange;
+mod peers;
+mod punch;
+mod relay;
+mod room;
+mod stealth;
+mod tor;
 mod ssb;
 
 use app::App;
 
-fn panic_hook(info: &PanicInfo<'_>) {
+fn panic_hook(info: &PanicHookInfo<'_>) {
     let location = info.location().unwrap(); // The current implementation always returns Some
 
     let msg = match info.payload().downcast_ref::<&'static str>() {
@@ -39,10 +54,62 @@ fn panic_hook(info: &PanicInfo<'_>) {
     );
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+const USAGE: &str = "Usage: scuttle-chat [--connect <multiserver address>]...
+       scuttle-chat relay";
+
+enum Command {
+    /// The chat UI, dialing the multiserver addresses given with
+    /// `--connect`
+    Chat(Vec<String>),
+    /// A headless room for other scuttle-chat clients
+    Relay,
+}
+
+fn parse_args() -> Result<Command, String> {
+    let mut connect = Vec::new();
+    let mut args = std::env::args().skip(1).peekable();
+
+    if args.peek().map(String::as_str) == Some("relay") {
+        return match args.nth(1) {
+            None => Ok(Command::Relay),
+            Some(_) => Err(USAGE.to_string()),
+        };
+    }
 
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--connect" => {
+                let address = args.next().ok_or_else(|| USAGE.to_string())?;
+                // report typos before the terminal is taken over
+                discovery::PeerAddr::lookup(&address)
+                    .map_err(|e| format!("Invalid --connect address: {}", e))?;
+                connect.push(address);
+            }
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    Ok(Command::Chat(connect))
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let connect = match parse_args() {
+        Ok(Command::Chat(connect)) => connect,
+        Ok(Command::Relay) => {
+            let config = match config::ConfigWatcher::default_path() {
+                Some(path) => config::ConfigWatcher::new(path).load()?,
+                None => config::Config::default(),
+            };
+            relay::run(&config)?;
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
 
-    std::panic::set_hook(Box::new(|info| panic_hook(info)));
+    std::panic::set_hook(Box::new(panic_hook));
     // Terminal initialization
     let stdout = io::stdout().into_raw_mode()?;
     let stdout = MouseTerminal::from(stdout);
@@ -52,7 +119,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     terminal.hide_cursor()?;
 
     // App
-    let mut app = App::new();
+    let mut app = App::new()?;
+    for address in connect.iter() {
+        app.connect_manually(address, None);
+    }
     app.run(&mut terminal)?;
 
     Ok(())
@@ -0,0 +1,183 @@
+use crate::config::{AnnounceMode, NetworkConfig};
+use crate::discovery::{
+    parse_feed_id, shared_network, AnnouncementKind, Discovery, DiscoveryServiceError, Mode,
+    PeerAddr, PeerAnnouncement, Protocol, ANNOUNCE_INTERVAL,
+};
+use crate::network::{Network, NetworkId};
+use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent, ServiceInfo};
+use ssb_crypto::PublicKey;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+const SERVICE_TYPE: &str = "_scuttle-chat._tcp.local.";
+/// TXT record keys, the feed id and the `NetworkId`s we're on, comma
+/// separated
+const FEED_ID_KEY: &str = "feed";
+const NETWORK_IDS_KEY: &str = "caps";
+
+/// Advertises a `_scuttle-chat._tcp` service over multicast DNS and
+/// browses for other instances, for networks that drop UDP broadcasts.
+pub struct MdnsDiscovery {
+    daemon: ServiceDaemon,
+    events: Receiver<ServiceEvent>,
+    ignore_broadcasts_from: Option<PublicKey>,
+    networks: Vec<(NetworkId, String)>,
+    /// Instances currently advertised, by mDNS full name
+    resolved: HashMap<String, PeerAnnouncement>,
+    queued: VecDeque<PeerAnnouncement>,
+    next_refresh: Instant,
+}
+
+fn feed_id(public_key: &PublicKey) -> String {
+    format!("@{}.ed25519", base64::encode(&public_key.0))
+}
+
+fn register(
+    daemon: &ServiceDaemon,
+    public_key: &PublicKey,
+    networks: &[Network],
+    listener_port: u16,
+) -> Result<(), DiscoveryServiceError> {
+    // instance names only need to be unique on the link, the feed id is
+    // in the TXT record
+    let instance = public_key.0[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    let network_ids = networks
+        .iter()
+        .map(|network| network.id.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    let properties = [
+        (FEED_ID_KEY, feed_id(public_key)),
+        (NETWORK_IDS_KEY, network_ids),
+    ];
+
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &instance,
+        &format!("{}.local.", instance),
+        (),
+        listener_port,
+        &properties[..],
+    )?
+    .enable_addr_auto();
+    daemon.register(service)?;
+    Ok(())
+}
+
+impl MdnsDiscovery {
+    pub fn new(
+        mode: Mode,
+        public_key: PublicKey,
+        config: &NetworkConfig,
+    ) -> Result<Self, DiscoveryServiceError> {
+        let daemon = ServiceDaemon::new()?;
+        let networks = config.networks();
+
+        // the TXT record names our feed to anyone who asks, so stealth
+        // and listen only modes just browse
+        if config.announce == AnnounceMode::Public {
+            register(&daemon, &public_key, &networks, config.listener_port)?;
+        }
+
+        let events = daemon.browse(SERVICE_TYPE)?;
+
+        Ok(MdnsDiscovery {
+            daemon,
+            events,
+            ignore_broadcasts_from: match mode {
+                Mode::Normal => Some(public_key),
+                Mode::Debug => None,
+            },
+            networks: networks.iter().map(|n| (n.id, n.name.clone())).collect(),
+            resolved: HashMap::new(),
+            queued: VecDeque::new(),
+            next_refresh: Instant::now() + ANNOUNCE_INTERVAL,
+        })
+    }
+
+    fn announcement(&self, info: &ServiceInfo) -> Option<PeerAnnouncement> {
+        let public_key = parse_feed_id(info.get_property_val_str(FEED_ID_KEY)?)?;
+        if self.ignore_broadcasts_from == Some(public_key) {
+            return None;
+        }
+
+        let mut network_ids = info
+            .get_property_val_str(NETWORK_IDS_KEY)
+            .unwrap_or("")
+            .split(',')
+            .filter_map(NetworkId::parse)
+            .collect::<Vec<NetworkId>>();
+        if network_ids.is_empty() {
+            network_ids.push(NetworkId::main_net());
+        }
+        let network = shared_network(&self.networks, &network_ids)?;
+
+        // link-local IPv6 addresses come without the scope id needed to
+        // dial them, so IPv4 addresses are preferred
+        let ip = info.get_addresses().iter().min_by_key(|ip| ip.is_ipv6())?;
+
+        Some(PeerAnnouncement {
+            peer: PeerAddr {
+                protocol: Protocol::Net,
+                socket_addr: SocketAddr::new(*ip, info.get_port()),
+                public_key,
+            },
+            network,
+            kind: AnnouncementKind::Chat,
+            // TXT records are registered once, so there's no fresh
+            // timestamp to sign
+            verified: false,
+        })
+    }
+}
+
+impl Discovery for MdnsDiscovery {
+    fn recv(&mut self) -> Result<PeerAnnouncement, DiscoveryServiceError> {
+        loop {
+            if let Some(announcement) = self.queued.pop_front() {
+                return Ok(announcement);
+            }
+
+            // mDNS only reports changes, so instances that are still
+            // advertised are repeated like beacons to keep them from
+            // expiring
+            let now = Instant::now();
+            if now >= self.next_refresh {
+                self.queued.extend(self.resolved.values().cloned());
+                self.next_refresh = now + ANNOUNCE_INTERVAL;
+                continue;
+            }
+
+            match self.events.recv_timeout(self.next_refresh - now) {
+                Ok(ServiceEvent::ServiceResolved(info)) => {
+                    if let Some(announcement) = self.announcement(&info) {
+                        self.resolved
+                            .insert(info.get_fullname().to_string(), announcement.clone());
+                        return Ok(announcement);
+                    }
+                }
+                Ok(ServiceEvent::ServiceRemoved(_, fullname)) => {
+                    self.resolved.remove(&fullname);
+                }
+                Ok(_) => {}
+                Err(_) if self.events.is_disconnected() => {
+                    return Err(
+                        io::Error::new(io::ErrorKind::BrokenPipe, "mDNS daemon stopped").into(),
+                    );
+                }
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+impl Drop for MdnsDiscovery {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
@@ -0,0 +1,278 @@
+use crate::box_stream::{BoxReader, BoxStreamError, BoxWriter};
+use byteorder::{BigEndian, ByteOrder};
+use serde_json::{json, Value};
+use snafu::ResultExt;
+use std::io::{self, Read, Write};
+
+/// muxrpc packet header: flags, body length and request number
+const HEADER_LEN: usize = 9;
+/// Largest body a box stream message carries
+const MAX_BOX_BODY: usize = 4096;
+/// Largest packet body we take, well over anything a room sends, so a
+/// peer can't make us buffer gigabytes for a single packet
+const MAX_PACKET_BODY: usize = 1024 * 1024;
+
+const FLAG_STREAM: u8 = 0b1000;
+const FLAG_END_OR_ERROR: u8 = 0b0100;
+
+#[derive(Snafu, Debug)]
+pub enum PacketError {
+    #[snafu(display("{}", source))]
+    BoxStreamFailed { source: BoxStreamError },
+    #[snafu(display(
+        "Packet body of {} bytes is over the {} byte limit",
+        len,
+        MAX_PACKET_BODY
+    ))]
+    BodyTooLarge { len: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyType {
+    Binary,
+    Utf8,
+    Json,
+}
+
+/// A packet of SSB's packet-stream protocol, which muxrpc calls and their
+/// streams are carried in. Requests have positive request numbers, the
+/// responses to them the negated number.
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub stream: bool,
+    pub end_or_error: bool,
+    pub body_type: BodyType,
+    pub req: i32,
+    pub body: Vec<u8>,
+}
+
+impl Packet {
+    /// Calls `name`, `kind` being "async", "source", "sink" or "duplex"
+    pub fn request(req: i32, name: &[&str], kind: &str, args: Value) -> Packet {
+        Packet {
+            stream: kind != "async",
+            end_or_error: false,
+            body_type: BodyType::Json,
+            req,
+            body: json!({ "name": name, "type": kind, "args": args })
+                .to_string()
+                .into_bytes(),
+        }
+    }
+
+    /// A chunk of a binary stream
+    pub fn data(req: i32, body: Vec<u8>) -> Packet {
+        Packet {
+            stream: true,
+            end_or_error: false,
+            body_type: BodyType::Binary,
+            req,
+            body,
+        }
+    }
+
+    /// A JSON item of a stream, or the answer to an async call
+    pub fn value(req: i32, stream: bool, value: &Value) -> Packet {
+        Packet {
+            stream,
+            end_or_error: false,
+            body_type: BodyType::Json,
+            req,
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    /// Ends our side of a stream
+    pub fn end(req: i32) -> Packet {
+        Packet {
+            stream: true,
+            end_or_error: true,
+            body_type: BodyType::Json,
+            req,
+            body: b"true".to_vec(),
+        }
+    }
+
+    pub fn error(req: i32, stream: bool, message: &str) -> Packet {
+        Packet {
+            stream,
+            end_or_error: true,
+            body_type: BodyType::Json,
+            req,
+            body: json!({ "name": "Error", "message": message, "stack": "" })
+                .to_string()
+                .into_bytes(),
+        }
+    }
+
+    pub fn json(&self) -> Option<Value> {
+        match self.body_type {
+            BodyType::Json => serde_json::from_slice(&self.body).ok(),
+            BodyType::Binary | BodyType::Utf8 => None,
+        }
+    }
+
+    /// The dotted method name and arguments of a request
+    pub fn call(&self) -> Option<(String, Vec<Value>)> {
+        let request = self.json()?;
+        let name = request["name"]
+            .as_array()?
+            .iter()
+            .map(|part| part.as_str())
+            .collect::<Option<Vec<&str>>>()?
+            .join(".");
+        let args = request["args"].as_array().cloned().unwrap_or_default();
+        Some((name, args))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut flags = match self.body_type {
+            BodyType::Binary => 0,
+            BodyType::Utf8 => 1,
+            BodyType::Json => 2,
+        };
+        if self.stream {
+            flags |= FLAG_STREAM;
+        }
+        if self.end_or_error {
+            flags |= FLAG_END_OR_ERROR;
+        }
+
+        let mut packet = vec![0; HEADER_LEN];
+        packet[0] = flags;
+        BigEndian::write_u32(&mut packet[1..5], self.body.len() as u32);
+        BigEndian::write_i32(&mut packet[5..9], self.req);
+        packet.extend_from_slice(&self.body);
+        packet
+    }
+}
+
+/// Reads packets off a box stream, whose messages don't line up with
+/// packet boundaries
+pub struct PacketReader<R: Read> {
+    box_reader: BoxReader<R>,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(box_reader: BoxReader<R>) -> PacketReader<R> {
+        PacketReader {
+            box_reader,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Returns false once the box stream said goodbye
+    fn fill(&mut self, len: usize) -> Result<bool, PacketError> {
+        while self.buf.len() < len {
+            match self.box_reader.recv().context(BoxStreamFailed)? {
+                Some(body) => self.buf.extend(body),
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// `None` once either the packet stream or the box stream says
+    /// goodbye. Packets over `MAX_PACKET_BODY` are an error, the stream
+    /// can't be read past them.
+    pub fn recv(&mut self) -> Result<Option<Packet>, PacketError> {
+        if !self.fill(HEADER_LEN)? {
+            return Ok(None);
+        }
+        let header = self.buf[..HEADER_LEN].to_vec();
+        if header.iter().all(|byte| *byte == 0) {
+            return Ok(None);
+        }
+
+        let body_len = BigEndian::read_u32(&header[1..5]) as usize;
+        if body_len > MAX_PACKET_BODY {
+            return Err(PacketError::BodyTooLarge { len: body_len });
+        }
+        if !self.fill(HEADER_LEN + body_len)? {
+            return Ok(None);
+        }
+        let body = self.buf[HEADER_LEN..HEADER_LEN + body_len].to_vec();
+        self.buf.drain(..HEADER_LEN + body_len);
+
+        Ok(Some(Packet {
+            stream: header[0] & FLAG_STREAM != 0,
+            end_or_error: header[0] & FLAG_END_OR_ERROR != 0,
+            body_type: match header[0] & 0b11 {
+                0 => BodyType::Binary,
+                1 => BodyType::Utf8,
+                _ => BodyType::Json,
+            },
+            req: BigEndian::read_i32(&header[5..9]),
+            body,
+        }))
+    }
+}
+
+/// Writes a packet, split over as many box stream messages as it takes
+pub fn send<W: Write>(box_writer: &mut BoxWriter<W>, packet: &Packet) -> io::Result<()> {
+    for chunk in packet.encode().chunks(MAX_BOX_BODY) {
+        box_writer.send(chunk.to_vec())?;
+    }
+    Ok(())
+}
+
+/// Says goodbye on the packet stream, then on the box stream
+pub fn send_goodbye<W: Write>(mut box_writer: BoxWriter<W>) -> io::Result<()> {
+    box_writer.send(vec![0; HEADER_LEN])?;
+    box_writer.send_goodbye()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ssb_crypto::secretbox::{Key, Nonce};
+    use ssb_crypto::NonceGen;
+
+    fn noncegen() -> NonceGen {
+        NonceGen::with_starting_nonce(Nonce([7; 24]))
+    }
+
+    fn packet_reader(bytes: &[u8]) -> PacketReader<&[u8]> {
+        PacketReader::new(BoxReader::new(bytes, Key([1; 32]), noncegen()))
+    }
+
+    #[test]
+    fn packets_span_box_stream_messages() {
+        let mut bytes = Vec::new();
+        let mut box_writer = BoxWriter::new(&mut bytes, Key([1; 32]), noncegen());
+        let args = json!([{ "origin": "@a", "portal": "@b", "target": "@c" }]);
+        let request = Packet::request(1, &["tunnel", "connect"], "duplex", args.clone());
+        let data = Packet::data(1, vec![42; 3 * MAX_BOX_BODY]);
+        send(&mut box_writer, &request).unwrap();
+        send(&mut box_writer, &data).unwrap();
+        send_goodbye(box_writer).unwrap();
+
+        let mut reader = packet_reader(&bytes);
+        let received = reader.recv().unwrap().unwrap();
+        assert!(received.stream);
+        assert_eq!(
+            received.call(),
+            Some(("tunnel.connect".to_string(), vec![args[0].clone()]))
+        );
+        let received = reader.recv().unwrap().unwrap();
+        assert_eq!(received.body_type, BodyType::Binary);
+        assert_eq!(received.body, data.body);
+        assert!(reader.recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused_before_they_are_read() {
+        let mut header = Packet::data(1, Vec::new()).encode();
+        BigEndian::write_u32(&mut header[1..5], MAX_PACKET_BODY as u32 + 1);
+        let mut bytes = Vec::new();
+        BoxWriter::new(&mut bytes, Key([1; 32]), noncegen())
+            .send(header)
+            .unwrap();
+
+        match packet_reader(&bytes).recv() {
+            Err(PacketError::BodyTooLarge { len }) => assert_eq!(len, MAX_PACKET_BODY + 1),
+            other => panic!("expected BodyTooLarge, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use sodiumoxide::crypto::{auth, hash::sha256};
+use ssb_crypto::NetworkKey;
+use std::fmt;
+
+/// Length of the secret handshake's first message, the client's HMAC'd
+/// ephemeral key
+pub const CLIENT_HELLO_LEN: usize = 64;
+
+/// A network key we handshake with, under the name shown in the UI
+#[derive(Clone)]
+pub struct Network {
+    pub name: String,
+    pub key: NetworkKey,
+    pub id: NetworkId,
+}
+
+impl Network {
+    pub fn new(name: &str, key: NetworkKey) -> Network {
+        Network {
+            name: name.to_string(),
+            id: NetworkId::from_key(&key),
+            key,
+        }
+    }
+
+    /// Whether a client hello was authenticated with this network's key,
+    /// i.e. the client is trying to handshake on this network
+    pub fn accepts_client_hello(&self, hello: &[u8; CLIENT_HELLO_LEN]) -> bool {
+        let (hmac, ephemeral_pk) = hello.split_at(32);
+
+        match (
+            auth::Tag::from_slice(hmac),
+            auth::Key::from_slice(self.key.as_slice()),
+        ) {
+            (Some(tag), Some(key)) => auth::verify(&tag, ephemeral_pk, &key),
+            _ => false,
+        }
+    }
+}
+
+/// Short public identifier of a network key, carried in discovery
+/// announcements so peers on other networks can be told apart without
+/// revealing the key itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NetworkId([u8; 4]);
+
+impl NetworkId {
+    pub fn from_key(key: &NetworkKey) -> NetworkId {
+        let digest = sha256::hash(key.as_slice());
+        let mut id = [0; 4];
+        id.copy_from_slice(&digest.0[..4]);
+        NetworkId(id)
+    }
+
+    pub fn main_net() -> NetworkId {
+        NetworkId::from_key(&NetworkKey::SSB_MAIN_NET)
+    }
+
+    pub fn parse(s: &str) -> Option<NetworkId> {
+        if s.len() != 8 {
+            return None;
+        }
+        let mut id = [0; 4];
+        for (i, byte) in id.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+        Some(NetworkId(id))
+    }
+}
+
+impl fmt::Display for NetworkId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes a base64 encoded 32 byte network key, aka SSB "caps"
+pub fn network_key_from_base64(s: &str) -> Option<NetworkKey> {
+    let bytes = base64::decode(s).ok()?;
+    NetworkKey::from_slice(&bytes)
+}
@@ -1,116 +1,377 @@
+use crate::config::NetworkConfig;
 use crate::discovery::{PeerAddr, Protocol};
+use crate::network::{Network, CLIENT_HELLO_LEN};
+use crate::ws;
 use snafu::ResultExt;
+use socks::Socks5Stream;
 use ssb_crypto::handshake::HandshakeKeys;
 use ssb_crypto::{NetworkKey, PublicKey, SecretKey};
 use ssb_handshake::HandshakeError;
 use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::sync::mpsc;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
 use crate::box_stream::{BoxReader, BoxStreamError, BoxWriter};
+use crate::peer_exchange;
 use crate::peer_manager::{PeerEvent, PeerManagerEvent};
+use crate::punch;
+use crate::room::Rooms;
+
+/// Upper bound for a whole handshake, so a stalling peer can't tie up
+/// the thread handshaking with it
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often a connection with nothing else to say sends a keepalive, so
+/// the peer can tell it from a dead one
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// A keepalive is the `\0ping` control message, see `peer_exchange`
+const KEEPALIVE: &str = "\u{0}ping";
 
 type WriterLoopHandle = thread::JoinHandle<Result<(), PeerConnectionError>>;
 type ReaderLoopHandle = thread::JoinHandle<Result<(), PeerConnectionError>>;
+type HeldReader = (
+    mpsc::Sender<PeerManagerEvent>,
+    BoxReader<Box<dyn Read + Send>>,
+);
+
+/// Tells connections to the same peer apart, e.g. when both sides dialed
+/// each other at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    fn next() -> ConnectionId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ConnectionId(NEXT_ID.fetch_add(1, Ordering::SeqCst))
+    }
+}
 
+/// Which side dialed the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Outbound,
+    Inbound,
+}
+
+/// Dropping a connection says goodbye to the peer and closes the stream
 pub struct PeerConnection {
+    pub id: ConnectionId,
     pub peer: PeerAddr,
-    pub peer_writer_tx: mpsc::Sender<String>,
-    _reader_loop_handle: ReaderLoopHandle,
+    pub direction: Direction,
+    /// Name of the network the handshake was made on
+    pub network: String,
+    peer_writer_tx: mpsc::Sender<String>,
+    /// When the peer last sent anything, keepalives included
+    last_read: Arc<Mutex<Instant>>,
+    /// Held back until `start_reading`, so nothing is read from a
+    /// connection we haven't decided to keep
+    reader: Option<Box<HeldReader>>,
+    _reader_loop_handle: Option<ReaderLoopHandle>,
     _writer_loop_handle: WriterLoopHandle,
 }
 
-type PeerMsg = String;
-
 #[derive(Snafu, Debug)]
 pub enum PeerConnectionError {
     #[snafu(display("Failed to read message from BoxReader: {}", source))]
     BoxReaderError { source: BoxStreamError },
     #[snafu(display("Failed to send message to BoxWriter: {}", source))]
     BoxWriterError { source: io::Error },
-    #[snafu(display("Failed to receive peer message from channel: {}", source))]
-    MsgReceiveFailed { source: mpsc::RecvError },
     #[snafu(display("Failed to perform handshake: {}", source))]
     HandshakeFailed { source: HandshakeError },
     #[snafu(display("Failed to clone TcpStream for BoxWriter: {}", source))]
     TcpStreamCloneFailed { source: io::Error },
     #[snafu(display("Timeout when attempting to connect to peer: {}", source))]
     CannotConnectToPeer { source: io::Error },
+    #[snafu(display("Failed to upgrade to a WebSocket: {}", source))]
+    WebSocketUpgradeFailed { source: io::Error },
+    #[snafu(display("Failed to connect to peer through proxy {}: {}", proxy, source))]
+    ProxyConnectFailed {
+        proxy: SocketAddr,
+        source: io::Error,
+    },
+    #[snafu(display("Onion peers can only be dialed through a socks_proxy"))]
+    NoProxyForOnion {},
+    #[snafu(display("Not connected to the peer's room"))]
+    NotInRoom {},
+    #[snafu(display("Rooms can only be reached on net: and onion: addresses"))]
+    UnsupportedRoomAddress {},
+    #[snafu(display("No network key configured for network \"{}\"", name))]
+    UnknownNetwork { name: String },
+    #[snafu(display("Failed to configure socket timeouts: {}", source))]
+    SocketTimeoutFailed { source: io::Error },
+    #[snafu(display("Inbound handshake from {} failed: {}", remote, source))]
+    InboundHandshakeFailed {
+        remote: SocketAddr,
+        source: Box<PeerConnectionError>,
+    },
+    #[snafu(display("Too many handshakes from {}, dropped connection", remote))]
+    RateLimited { remote: SocketAddr },
+    #[snafu(display("Too many pending handshakes, dropped connection from {}", remote))]
+    TooManyPendingHandshakes { remote: SocketAddr },
+    #[snafu(display("Not connected to peer"))]
+    NotConnected {},
+    #[snafu(display("Failed to pass message to BoxWriter: {}", source))]
+    MsgSendFailed { source: mpsc::SendError<String> },
+}
+
+/// A stream handshakes and box streams run over, a TCP connection, a
+/// WebSocket or a room tunnel
+pub trait Transport: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    /// Closes both directions, for every clone
+    fn shutdown(&self) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// Makes every read and write on a stream time out once a deadline for
+/// the whole exchange has passed, rather than per call
+pub struct DeadlineStream<'a, S: Transport> {
+    stream: &'a mut S,
+    deadline: Instant,
+}
+
+impl<'a, S: Transport> DeadlineStream<'a, S> {
+    fn remaining(&self) -> io::Result<Duration> {
+        match self.deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if remaining > Duration::from_millis(0) => Ok(remaining),
+            _ => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Handshake timed out",
+            )),
+        }
+    }
+}
+
+impl<'a, S: Transport> Read for DeadlineStream<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl<'a, S: Transport> Write for DeadlineStream<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Replays bytes we already read from a stream before reading on from
+/// the stream itself
+struct PrefixedStream<'a, S> {
+    prefix: &'a [u8],
+    stream: &'a mut S,
+}
+
+impl<'a, S: Read> Read for PrefixedStream<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.prefix.is_empty() {
+            return self.stream.read(buf);
+        }
+        self.prefix.read(buf)
+    }
+}
+
+impl<'a, S: Write> Write for PrefixedStream<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
 }
 
 fn spawn_reader_loop<R>(
     tx: mpsc::Sender<PeerManagerEvent>,
     peer: PeerAddr,
+    connection_id: ConnectionId,
+    last_read: Arc<Mutex<Instant>>,
     mut box_reader: BoxReader<R>,
 ) -> ReaderLoopHandle
 where
     R: Read + Send + 'static,
 {
     thread::spawn(move || -> Result<(), PeerConnectionError> {
-        loop {
-            let maybe_bytes = box_reader.recv().context(BoxReaderError)?;
-
-            let peer_msg = match maybe_bytes {
-                Some(raw_bytes) => String::from_utf8(raw_bytes.clone())
-                    .unwrap_or(format!("Raw bytes: {:?}", raw_bytes)),
-                None => "Goodbye!".to_string(),
+        let reason = loop {
+            let raw_bytes = match box_reader.recv().context(BoxReaderError) {
+                Ok(Some(raw_bytes)) => raw_bytes,
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
             };
+            *last_read.lock().unwrap() = Instant::now();
+
+            // keepalives are control messages too, and like the ones
+            // we don't know from newer versions, they're skipped
+            if peer_exchange::is_control_message(&raw_bytes) {
+                if let Some(peers) = peer_exchange::decode(&raw_bytes) {
+                    let _ = tx.send(PeerManagerEvent {
+                        peer: Some(peer),
+                        event: PeerEvent::PeersExchanged(peers),
+                    });
+                }
+                continue;
+            }
 
-            tx.send(PeerManagerEvent {
-                peer,
+            let peer_msg = String::from_utf8(raw_bytes.clone())
+                .unwrap_or(format!("Raw bytes: {:?}", raw_bytes));
+
+            // should have error handling, but this only
+            // happens if the main event_bus dies ?
+            let _ = tx.send(PeerManagerEvent {
+                peer: Some(peer),
                 event: PeerEvent::MessageReceived(peer_msg),
             });
-            // should have error handling, but this only 
-            // happens if the main event_bus dies ?
-        }
+        };
+
+        let _ = tx.send(PeerManagerEvent {
+            peer: Some(peer),
+            event: PeerEvent::ConnectionClosed(connection_id, reason),
+        });
+        Ok(())
     })
 }
 
-fn spawn_writer_loop<W>(mut box_writer: BoxWriter<W>) -> (mpsc::Sender<String>, WriterLoopHandle)
+/// Writes messages until every sender is dropped, then says goodbye and
+/// shuts the stream down. Sends a keepalive whenever there's been nothing
+/// to write for `KEEPALIVE_INTERVAL`.
+fn spawn_writer_loop<W, S>(
+    mut box_writer: BoxWriter<W>,
+    stream: S,
+) -> (mpsc::Sender<String>, WriterLoopHandle)
 where
     W: Write + Send + 'static,
+    S: Transport,
 {
     let (tx, rx) = mpsc::channel::<String>();
-    let handle: WriterLoopHandle = thread::spawn(move || loop {
-        let peer_msg = rx
-            .recv()
-            .map(String::into_bytes)
-            .context(MsgReceiveFailed)?;
-        box_writer.send(peer_msg).context(BoxWriterError)?;
+    let handle: WriterLoopHandle = thread::spawn(move || {
+        loop {
+            let peer_msg = match rx.recv_timeout(KEEPALIVE_INTERVAL) {
+                Ok(peer_msg) => peer_msg,
+                Err(RecvTimeoutError::Timeout) => KEEPALIVE.to_string(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            box_writer
+                .send(peer_msg.into_bytes())
+                .context(BoxWriterError)?;
+        }
+
+        let goodbye = box_writer.send_goodbye().context(BoxWriterError);
+        let _ = stream.shutdown();
+        goodbye
     });
 
     (tx, handle)
 }
 
 impl PeerConnection {
-    pub fn from_handshake<F>(
+    pub fn from_handshake<S, F>(
         event_bus: mpsc::Sender<PeerManagerEvent>,
-        mut tcp_stream: TcpStream,
+        mut tcp_stream: S,
+        direction: Direction,
         perform_handshake: F,
     ) -> Result<PeerConnection, PeerConnectionError>
     where
-        F: Fn(&mut TcpStream) -> Result<(PeerAddr, HandshakeKeys), HandshakeError> + Send + 'static,
+        S: Transport,
+        F: Fn(&mut DeadlineStream<S>) -> Result<(PeerAddr, String, HandshakeKeys), HandshakeError>
+            + Send
+            + 'static,
     {
-        let (peer, hs_keys) = perform_handshake(&mut tcp_stream).context(HandshakeFailed)?;
+        let (peer, network, hs_keys) = perform_handshake(&mut DeadlineStream {
+            stream: &mut tcp_stream,
+            deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+        })
+        .context(HandshakeFailed)?;
+
+        // the box stream loops block until the peer says something
+        tcp_stream
+            .set_read_timeout(None)
+            .context(SocketTimeoutFailed)?;
+        tcp_stream
+            .set_write_timeout(None)
+            .context(SocketTimeoutFailed)?;
+
+        let id = ConnectionId::next();
 
         let write_stream = tcp_stream.try_clone().context(TcpStreamCloneFailed)?;
-        let mut box_writer =
-            BoxWriter::new(write_stream, hs_keys.write_key, hs_keys.write_noncegen);
-        let (peer_writer_tx, _writer_loop_handle) = spawn_writer_loop(box_writer);
+        let shutdown_stream = tcp_stream.try_clone().context(TcpStreamCloneFailed)?;
+
+        let box_writer = BoxWriter::new(write_stream, hs_keys.write_key, hs_keys.write_noncegen);
+        let (peer_writer_tx, _writer_loop_handle) = spawn_writer_loop(box_writer, shutdown_stream);
 
-        let mut box_reader = BoxReader::new(tcp_stream, hs_keys.read_key, hs_keys.read_noncegen);
-        let _reader_loop_handle = spawn_reader_loop(event_bus.clone(), peer.clone(), box_reader);
+        let read_stream: Box<dyn Read + Send> = Box::new(tcp_stream);
+        let box_reader = BoxReader::new(read_stream, hs_keys.read_key, hs_keys.read_noncegen);
 
         let peer_connection = PeerConnection {
+            id,
             peer,
+            direction,
+            network,
             peer_writer_tx,
-            _reader_loop_handle,
+            last_read: Arc::new(Mutex::new(Instant::now())),
+            reader: Some(Box::new((event_bus, box_reader))),
+            _reader_loop_handle: None,
             _writer_loop_handle,
         };
 
         Ok(peer_connection)
     }
+
+    /// Starts passing the peer's messages on to the event bus
+    pub fn start_reading(&mut self) {
+        if let Some((event_bus, box_reader)) = self.reader.take().map(|reader| *reader) {
+            self._reader_loop_handle = Some(spawn_reader_loop(
+                event_bus,
+                self.peer,
+                self.id,
+                self.last_read.clone(),
+                box_reader,
+            ));
+        }
+    }
+
+    pub fn send(&self, msg: String) -> Result<(), PeerConnectionError> {
+        self.peer_writer_tx.send(msg).context(MsgSendFailed)
+    }
+
+    /// How long since the peer last sent anything
+    pub fn idle(&self) -> Duration {
+        self.last_read.lock().unwrap().elapsed()
+    }
+
+    /// Public key of the side that dialed the connection
+    pub fn dialer(&self, our_public_key: &PublicKey) -> PublicKey {
+        match self.direction {
+            Direction::Outbound => *our_public_key,
+            Direction::Inbound => self.peer.public_key,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -118,7 +379,12 @@ pub struct Handshaker {
     event_bus: mpsc::Sender<PeerManagerEvent>,
     public_key: PublicKey,
     secret_key: SecretKey,
-    network_key: NetworkKey,
+    networks: Vec<Network>,
+    socks_proxy: Option<SocketAddr>,
+    proxy_all: bool,
+    hole_punching: bool,
+    /// Rooms we're connected to, to tunnel through
+    rooms: Rooms,
 }
 
 impl Handshaker {
@@ -126,58 +392,357 @@ impl Handshaker {
         event_bus: mpsc::Sender<PeerManagerEvent>,
         public_key: PublicKey,
         secret_key: SecretKey,
-        network_key: NetworkKey,
+        config: &NetworkConfig,
     ) -> Handshaker {
         Handshaker {
             event_bus,
             public_key,
             secret_key,
-            network_key,
+            networks: config.networks(),
+            socks_proxy: config.socks_proxy,
+            proxy_all: config.proxy_all,
+            hole_punching: config.hole_punching,
+            rooms: Rooms::default(),
         }
     }
 
-    pub fn client_handshake(&self, peer: PeerAddr) -> Result<PeerConnection, PeerConnectionError> {
-        let tcp_stream =
-            TcpStream::connect_timeout(&peer.socket_addr, std::time::Duration::from_millis(1000))
-                .context(CannotConnectToPeer)?;
+    pub fn rooms(&self) -> &Rooms {
+        &self.rooms
+    }
+
+    fn proxied(&self, peer: &PeerAddr) -> bool {
+        peer.protocol.is_onion() || (self.socks_proxy.is_some() && self.proxy_all)
+    }
+
+    /// Whether to punch through NATs to attendants of `room`, which takes
+    /// dialing the room ourselves rather than through a proxy
+    pub fn punches_through(&self, room: &PeerAddr) -> bool {
+        self.hole_punching && !self.proxied(room)
+    }
 
+    /// Connects to the peer, through the SOCKS5 proxy for onion peers, or
+    /// for every peer with `proxy_all`
+    fn dial(&self, peer: &PeerAddr) -> Result<TcpStream, PeerConnectionError> {
+        let proxy = match (self.socks_proxy, peer.protocol) {
+            (None, Protocol::Onion(_)) => return Err(PeerConnectionError::NoProxyForOnion {}),
+            (Some(proxy), Protocol::Onion(_)) => proxy,
+            (Some(proxy), _) if self.proxy_all => proxy,
+            _ => {
+                return TcpStream::connect_timeout(&peer.socket_addr, Duration::from_millis(1000))
+                    .context(CannotConnectToPeer)
+            }
+        };
+
+        // onion names are passed on as they are, for the proxy to resolve
+        let stream = match peer.protocol {
+            Protocol::Onion(onion) => {
+                Socks5Stream::connect(proxy, (onion.host().as_str(), peer.socket_addr.port()))
+            }
+            Protocol::Net | Protocol::WebSocket | Protocol::Tunnel(_) => {
+                Socks5Stream::connect(proxy, peer.socket_addr)
+            }
+        }
+        .context(ProxyConnectFailed { proxy })?;
+
+        Ok(stream.into_inner())
+    }
+
+    pub fn client_handshake(
+        &self,
+        peer: PeerAddr,
+        network: &str,
+    ) -> Result<PeerConnection, PeerConnectionError> {
+        let network = self
+            .networks
+            .iter()
+            .find(|n| n.name == network)
+            .cloned()
+            .ok_or_else(|| PeerConnectionError::UnknownNetwork {
+                name: network.to_string(),
+            })?;
+
+        match peer.protocol {
+            Protocol::Net | Protocol::Onion(_) => {
+                let tcp_stream = self.dial(&peer)?;
+                self.client_handshake_over(tcp_stream, peer, network)
+            }
+            Protocol::WebSocket => {
+                let tcp_stream = self.dial(&peer)?;
+                let ws_stream = ws::connect(tcp_stream, &peer.socket_addr.to_string())
+                    .context(WebSocketUpgradeFailed)?;
+                self.client_handshake_over(ws_stream, peer, network)
+            }
+            Protocol::Tunnel(portal) => {
+                // a path punched through both NATs spares the room relaying
+                // every byte, the tunnel is the fallback
+                if self.hole_punching {
+                    if let Some(stream) = self.rooms.punch(&portal, &peer.public_key) {
+                        if let Ok(connection) =
+                            self.client_handshake_over(stream, peer, network.clone())
+                        {
+                            return Ok(connection);
+                        }
+                    }
+                }
+
+                let tunnel = self
+                    .rooms
+                    .open(&portal, &peer.public_key)
+                    .ok_or(PeerConnectionError::NotInRoom {})?;
+                self.client_handshake_over(tunnel, peer, network)
+            }
+        }
+    }
+
+    /// The client side of the secret handshake, over a stream already
+    /// connected to the peer
+    fn client_handshake_over<S: Transport>(
+        &self,
+        stream: S,
+        peer: PeerAddr,
+        network: Network,
+    ) -> Result<PeerConnection, PeerConnectionError> {
         let config = self.clone();
 
-        PeerConnection::from_handshake(self.event_bus.clone(), tcp_stream, move |stream| {
-            let keys = ssb_handshake::client(
-                stream,
-                config.network_key.clone(),
-                config.public_key,
-                config.secret_key.clone(),
-                peer.public_key,
-            )?;
-            Ok((peer.clone(), keys))
-        })
+        PeerConnection::from_handshake(
+            self.event_bus.clone(),
+            stream,
+            Direction::Outbound,
+            move |stream| {
+                let keys = ssb_handshake::client(
+                    stream,
+                    network.key.clone(),
+                    config.public_key,
+                    config.secret_key.clone(),
+                    peer.public_key,
+                )?;
+                Ok((peer, network.name.clone(), keys))
+            },
+        )
     }
 
+    /// Handshakes with a client that connected to one of our listeners,
+    /// `protocol` telling which
     pub fn server_handshake(
         &self,
         stream: TcpStream,
+        protocol: Protocol,
+        client_addr: SocketAddr,
+    ) -> Result<PeerConnection, PeerConnectionError> {
+        match protocol {
+            Protocol::Net | Protocol::Onion(_) | Protocol::Tunnel(_) => {
+                self.server_handshake_over(stream, client_addr, Protocol::Net)
+            }
+            Protocol::WebSocket => {
+                let ws_stream = ws::accept(stream).context(WebSocketUpgradeFailed)?;
+                self.server_handshake_over(ws_stream, client_addr, Protocol::Net)
+            }
+        }
+    }
+
+    /// Handshakes with a peer that dialed us through the room with key
+    /// `portal`, over its tunnel or a connection punched through our NATs
+    pub fn tunnel_handshake<S: Transport>(
+        &self,
+        stream: S,
+        portal: PublicKey,
+    ) -> Result<PeerConnection, PeerConnectionError> {
+        let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        self.server_handshake_over(stream, unspecified, Protocol::Tunnel(portal))
+    }
+
+    /// The server side of the secret handshake, the client being listed
+    /// at `client_addr` over `protocol`
+    fn server_handshake_over<S: Transport>(
+        &self,
+        stream: S,
+        client_addr: SocketAddr,
+        protocol: Protocol,
     ) -> Result<PeerConnection, PeerConnectionError> {
         let config = self.clone();
 
-        PeerConnection::from_handshake(self.event_bus.clone(), stream, move |stream| {
-            let client_addr = stream.peer_addr()?;
+        PeerConnection::from_handshake(
+            self.event_bus.clone(),
+            stream,
+            Direction::Inbound,
+            move |stream| {
+                let (network, client_pk, keys) = config.server_secret_handshake(stream)?;
+
+                let peer = PeerAddr {
+                    public_key: client_pk,
+                    socket_addr: client_addr,
+                    protocol,
+                };
+
+                Ok((peer, network, keys))
+            },
+        )
+    }
 
-            let (client_pk, keys) = ssb_handshake::server_with_client_pk(
+    /// Connects and handshakes with a room server, which is on the SSB
+    /// main net whatever networks we're on
+    pub fn room_handshake(
+        &self,
+        room: &PeerAddr,
+    ) -> Result<(TcpStream, HandshakeKeys), PeerConnectionError> {
+        if let Protocol::WebSocket | Protocol::Tunnel(_) = room.protocol {
+            return Err(PeerConnectionError::UnsupportedRoomAddress {});
+        }
+
+        // punching reuses the port of our room connection
+        let mut stream = if self.punches_through(room) {
+            punch::dial(&room.socket_addr, Duration::from_millis(1000))
+                .context(CannotConnectToPeer)?
+        } else {
+            self.dial(room)?
+        };
+        let keys = ssb_handshake::client(
+            &mut DeadlineStream {
+                stream: &mut stream,
+                deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+            },
+            NetworkKey::SSB_MAIN_NET,
+            self.public_key,
+            self.secret_key.clone(),
+            room.public_key,
+        )
+        .context(HandshakeFailed)?;
+
+        stream.set_read_timeout(None).context(SocketTimeoutFailed)?;
+        stream
+            .set_write_timeout(None)
+            .context(SocketTimeoutFailed)?;
+        Ok((stream, keys))
+    }
+
+    /// The relay's side of `room_handshake`, with a room client on the SSB
+    /// main net
+    pub fn relay_handshake(
+        &self,
+        stream: &mut TcpStream,
+    ) -> Result<(PublicKey, HandshakeKeys), PeerConnectionError> {
+        let (client_pk, keys) = ssb_handshake::server_with_client_pk(
+            &mut DeadlineStream {
                 stream,
-                config.network_key.clone(),
-                config.public_key,
-                config.secret_key.clone(),
-            )?;
-
-            let peer = PeerAddr {
-                public_key: client_pk,
-                socket_addr: client_addr,
-                protocol: Protocol::Net,
+                deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+            },
+            NetworkKey::SSB_MAIN_NET,
+            self.public_key,
+            self.secret_key.clone(),
+        )
+        .context(HandshakeFailed)?;
+
+        stream.set_read_timeout(None).context(SocketTimeoutFailed)?;
+        stream
+            .set_write_timeout(None)
+            .context(SocketTimeoutFailed)?;
+        Ok((client_pk, keys))
+    }
+
+    /// The server side of the secret handshake, on whichever of our
+    /// networks the client dialed into
+    fn server_secret_handshake<S: Read + Write>(
+        &self,
+        stream: &mut S,
+    ) -> Result<(String, PublicKey, HandshakeKeys), HandshakeError> {
+        // The client hello is authenticated with the network key, so it
+        // tells us which of our networks the client is dialing into
+        let mut hello = [0; CLIENT_HELLO_LEN];
+        stream.read_exact(&mut hello)?;
+        let network = self
+            .networks
+            .iter()
+            .find(|n| n.accepts_client_hello(&hello))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Client hello doesn't match any of our network keys",
+                )
+            })?;
+
+        let (client_pk, keys) = ssb_handshake::server_with_client_pk(
+            &mut PrefixedStream {
+                prefix: &hello,
+                stream,
+            },
+            network.key.clone(),
+            self.public_key,
+            self.secret_key.clone(),
+        )?;
+
+        Ok((network.name.clone(), client_pk, keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ssb_crypto::generate_longterm_keypair;
+    use std::net::TcpListener;
+
+    const ONION: &str = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd.onion";
+
+    /// Answers one client's SOCKS5 CONNECT with success, returning the
+    /// address type, host and port it asked for
+    fn socks5_server(listener: TcpListener) -> thread::JoinHandle<(u8, String, u16)> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut greeting = [0; 2];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting[0], 5);
+            let mut methods = vec![0; greeting[1] as usize];
+            stream.read_exact(&mut methods).unwrap();
+            assert!(methods.contains(&0), "no auth isn't offered");
+            stream.write_all(&[5, 0]).unwrap();
+
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..3], &[5, 1, 0], "not a CONNECT");
+            let host = match request[3] {
+                3 => {
+                    let mut len = [0; 1];
+                    stream.read_exact(&mut len).unwrap();
+                    let mut name = vec![0; len[0] as usize];
+                    stream.read_exact(&mut name).unwrap();
+                    String::from_utf8(name).unwrap()
+                }
+                1 => {
+                    let mut ip = [0; 4];
+                    stream.read_exact(&mut ip).unwrap();
+                    std::net::Ipv4Addr::from(ip).to_string()
+                }
+                atyp => panic!("unexpected address type {}", atyp),
             };
+            let mut port = [0; 2];
+            stream.read_exact(&mut port).unwrap();
 
-            Ok((peer, keys))
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            (request[3], host, u16::from_be_bytes(port))
         })
     }
+
+    #[test]
+    fn onion_peers_are_dialed_by_name_through_the_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = NetworkConfig {
+            socks_proxy: Some(listener.local_addr().unwrap()),
+            ..NetworkConfig::default()
+        };
+        let proxy = socks5_server(listener);
+
+        let (pk, sk) = generate_longterm_keypair();
+        let (event_bus, _events) = mpsc::channel();
+        let handshaker = Handshaker::new(event_bus, pk, sk, &config);
+
+        let peer: PeerAddr = format!("onion:{}:8008~shs:{}", ONION, base64::encode(&pk.0))
+            .parse()
+            .unwrap();
+        handshaker.dial(&peer).unwrap();
+
+        let (atyp, host, port) = proxy.join().unwrap();
+        assert_eq!(atyp, 3, "onion names go to the proxy as domain names");
+        assert_eq!(host, ONION);
+        assert_eq!(port, 8008);
+    }
 }
@@ -0,0 +1,107 @@
+use crate::discovery::PeerAddr;
+use std::time::Duration;
+
+/// How often connected peers are told about the peers we see
+pub const PEER_EXCHANGE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Control messages start with a NUL byte, which typed chat messages
+/// never contain
+pub const CONTROL_PREFIX: u8 = 0;
+/// A peer exchange is `\0pex` followed by one multiserver address per
+/// line. Link-local addresses are sent without their scope id, which only
+/// means something to us.
+const PEER_EXCHANGE: &str = "\u{0}pex";
+/// Addresses taken from a single peer exchange
+const MAX_EXCHANGED_PEERS: usize = 32;
+
+pub fn is_control_message(raw_bytes: &[u8]) -> bool {
+    raw_bytes.first() == Some(&CONTROL_PREFIX)
+}
+
+pub fn encode(peers: &[PeerAddr]) -> String {
+    let mut msg = PEER_EXCHANGE.to_string();
+    for peer in peers.iter().take(MAX_EXCHANGED_PEERS) {
+        msg.push('\n');
+        msg.push_str(&peer.without_scope().to_string());
+    }
+    msg
+}
+
+/// The addresses in a peer exchange, `None` for other control messages.
+/// Addresses we can't parse are skipped.
+pub fn decode(raw_bytes: &[u8]) -> Option<Vec<PeerAddr>> {
+    let msg = std::str::from_utf8(raw_bytes).ok()?;
+    let mut lines = msg.split('\n');
+    if lines.next()? != PEER_EXCHANGE {
+        return None;
+    }
+
+    Some(
+        lines
+            .take(MAX_EXCHANGED_PEERS)
+            .filter_map(|line| line.parse().ok())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::Protocol;
+    use ssb_crypto::PublicKey;
+
+    fn peer(n: u8) -> PeerAddr {
+        PeerAddr {
+            protocol: Protocol::Net,
+            socket_addr: ([10, 0, 0, n], 8008).into(),
+            public_key: PublicKey::from_slice(&[n; 32]).unwrap(),
+        }
+    }
+
+    fn feed_ids(peers: &[PeerAddr]) -> Vec<String> {
+        peers.iter().map(PeerAddr::feed_id).collect()
+    }
+
+    #[test]
+    fn exchanged_peers_decode_to_what_was_encoded() {
+        let peers = [peer(1), peer(2), peer(3)];
+        let msg = encode(&peers);
+        assert!(is_control_message(msg.as_bytes()));
+
+        let decoded = decode(msg.as_bytes()).unwrap();
+        assert_eq!(feed_ids(&decoded), feed_ids(&peers));
+        assert_eq!(decoded[1].socket_addr, peers[1].socket_addr);
+    }
+
+    #[test]
+    fn other_messages_are_not_peer_exchanges() {
+        assert!(!is_control_message(b"hello"));
+        assert!(is_control_message(b"\0ping"));
+        assert!(decode(b"\0ping").is_none());
+        assert!(decode(b"hello").is_none());
+        assert_eq!(decode(b"\0pex\nnot an address").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn exchanges_are_capped_both_ways() {
+        let peers = (1..=40).map(peer).collect::<Vec<PeerAddr>>();
+        let decoded = decode(encode(&peers).as_bytes()).unwrap();
+        assert_eq!(decoded.len(), MAX_EXCHANGED_PEERS);
+
+        // from a peer that doesn't cap what it sends
+        let mut msg = PEER_EXCHANGE.to_string();
+        for peer in peers.iter() {
+            msg.push('\n');
+            msg.push_str(&peer.to_string());
+        }
+        assert_eq!(decode(msg.as_bytes()).unwrap().len(), MAX_EXCHANGED_PEERS);
+    }
+
+    #[test]
+    fn link_local_addresses_are_sent_without_their_scope() {
+        let mut scoped = peer(1);
+        scoped.socket_addr = "[fe80::1%3]:8008".parse().unwrap();
+        let msg = encode(&[scoped]);
+        assert!(msg.contains("net:[fe80::1]:8008~shs:"));
+    }
+}
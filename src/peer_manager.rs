@@ -1,103 +1,465 @@
-use crate::discovery::{PeerAddr, PEER_DISCOVERY_PORT};
-use crate::peer_connection::{Handshaker, PeerConnection, PeerConnectionError};
-use ssb_crypto::{NetworkKey, PublicKey, SecretKey};
+use crate::chat::FeedId;
+use crate::config::NetworkConfig;
+use crate::discovery::{unmap_ipv4, PeerAddr, Protocol, PEER_DISCOVERY_PORT};
+use crate::peer_connection::{ConnectionId, Handshaker, PeerConnection, PeerConnectionError};
+use crate::room::{self, RoomDiscovery, RoomError};
+use net2::TcpBuilder;
+use ssb_crypto::{PublicKey, SecretKey};
+use std::collections::HashMap;
 use std::io;
-use std::net::TcpListener;
-use std::sync::mpsc;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant};
 
-const HANDSHAKE_NETWORK_KEY: NetworkKey = NetworkKey::SSB_MAIN_NET;
 pub const HANDSHAKE_LISTENER_PORT: u16 = PEER_DISCOVERY_PORT;
 
+/// Inbound handshakes allowed to run at the same time
+const MAX_PENDING_HANDSHAKES: usize = 32;
+/// Inbound handshakes allowed from a single IP per `RATE_LIMIT_WINDOW`
+const MAX_HANDSHAKES_PER_IP: usize = 5;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(10);
+/// A connection the peer said nothing on for this long, three missed
+/// keepalives, is taken for dead
+const STALE_CONNECTION: Duration = Duration::from_secs(90);
+
 pub struct PeerManager {
     event_bus: mpsc::Sender<PeerManagerEvent>,
     handshake_listener: Option<thread::JoinHandle<io::Result<()>>>,
+    websocket_listener: Option<thread::JoinHandle<io::Result<()>>>,
     handshaker: Handshaker,
-    pub connections: Vec<PeerConnection>,
+    listener_port: u16,
+    /// Port of the optional WebSocket listener
+    websocket_port: Option<u16>,
+    public_key: PublicKey,
+    connections: HashMap<FeedId, PeerConnection>,
+}
+
+/// What became of a connection handed to `PeerManager::register`
+pub enum Registration {
+    /// First connection to the peer
+    New,
+    /// Took over from an older connection to the peer, which was closed
+    Replaced,
+    /// The peer was already connected and the existing connection won the
+    /// tie-break, so the new one was closed
+    Duplicate,
 }
 
 type PeerMsg = String;
 
 pub struct PeerManagerEvent {
-    pub peer: PeerAddr,
+    /// `None` for inbound handshakes that failed before the client proved
+    /// who it is
+    pub peer: Option<PeerAddr>,
     pub event: PeerEvent,
 }
 
 pub enum PeerEvent {
-    HandshakeSuccessful(PeerConnection),
+    HandshakeSuccessful(Box<PeerConnection>),
     MessageReceived(PeerMsg),
-    // need to implement again when the
-    // ConnectionClosed event gets called
+    /// Peers the peer has seen recently, see `peer_exchange`
+    PeersExchanged(Vec<PeerAddr>),
     HandshakeFailed(PeerConnectionError),
-    ConnectionClosed(Result<(), PeerConnectionError>),
+    ConnectionClosed(ConnectionId, Result<(), PeerConnectionError>),
+    /// Connected to the room, its attendants are listed as they come
+    RoomJoined,
+    /// Lost or failed to reach the room at the address, it's dialed again
+    /// a little later
+    RoomLeft(String, RoomError),
 }
 
-use ssb_handshake::HandshakeError;
+fn bind_dual_stack(port: u16) -> io::Result<TcpListener> {
+    TcpBuilder::new_v6()?
+        .only_v6(false)?
+        .reuse_address(true)?
+        .bind((Ipv6Addr::UNSPECIFIED, port))?
+        .listen(128)
+}
+
+/// One dual stack socket takes both IPv4 and IPv6 connections, hosts
+/// without IPv6 fall back to IPv4 only
+pub fn bind_listener(port: u16) -> io::Result<TcpListener> {
+    match bind_dual_stack(port) {
+        Ok(listener) => Ok(listener),
+        Err(_) => TcpListener::bind(("0.0.0.0", port)),
+    }
+}
+
+/// Sliding window count of recent inbound connections per IP
+struct RateLimiter {
+    attempts: HashMap<IpAddr, Vec<Instant>>,
+}
+
+impl RateLimiter {
+    fn new() -> RateLimiter {
+        RateLimiter {
+            attempts: HashMap::new(),
+        }
+    }
+
+    fn allow(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+
+        self.attempts.retain(|_, attempts| {
+            attempts.retain(|t| now.duration_since(*t) < RATE_LIMIT_WINDOW);
+            !attempts.is_empty()
+        });
+
+        let attempts = self.attempts.entry(ip).or_default();
+        if attempts.len() >= MAX_HANDSHAKES_PER_IP {
+            false
+        } else {
+            attempts.push(now);
+            true
+        }
+    }
+}
+
+/// Counts a handshake worker as pending until it's dropped
+pub struct PendingHandshake(Arc<AtomicUsize>);
+
+impl PendingHandshake {
+    fn start(pending: &Arc<AtomicUsize>) -> Option<PendingHandshake> {
+        if pending.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_HANDSHAKES {
+            pending.fetch_sub(1, Ordering::SeqCst);
+            None
+        } else {
+            Some(PendingHandshake(pending.clone()))
+        }
+    }
+}
+
+impl Drop for PendingHandshake {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Whether a new connection to a peer takes over from the existing one.
+/// The same dialer means the peer reconnected, so the old connection is
+/// stale, and a connection gone quiet is stale whoever dialed it.
+fn replaces(new_dialer: &PublicKey, existing_dialer: &PublicKey, existing_idle: Duration) -> bool {
+    new_dialer <= existing_dialer || existing_idle >= STALE_CONNECTION
+}
+
+/// Serves every client connecting to `listener` on a thread of its own,
+/// within the rate limits. Clients over the limits are dropped and passed
+/// to `reject`.
+pub fn accept_connections<S, R>(
+    listener: TcpListener,
+    serve: S,
+    reject: R,
+) -> thread::JoinHandle<io::Result<()>>
+where
+    S: Fn(TcpStream, SocketAddr, PendingHandshake) + Clone + Send + 'static,
+    R: Fn(PeerConnectionError) + Send + 'static,
+{
+    thread::spawn(move || -> io::Result<()> {
+        let pending = Arc::new(AtomicUsize::new(0));
+        let mut rate_limiter = RateLimiter::new();
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let remote = match stream.peer_addr() {
+                Ok(remote) => remote,
+                Err(_) => continue,
+            };
+
+            // dropping the stream closes the connection
+            if !rate_limiter.allow(remote.ip()) {
+                reject(PeerConnectionError::RateLimited { remote });
+                continue;
+            }
+            let pending_handshake = match PendingHandshake::start(&pending) {
+                Some(pending_handshake) => pending_handshake,
+                None => {
+                    reject(PeerConnectionError::TooManyPendingHandshakes { remote });
+                    continue;
+                }
+            };
+
+            let serve = serve.clone();
+            // `serve` drops the pending handshake once the handshake is
+            // done, the connection may outlive it
+            thread::spawn(move || {
+                // the dual stack listener sees IPv4 clients as mapped
+                // IPv6 addresses
+                serve(stream, unmap_ipv4(remote), pending_handshake);
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Handshakes with every client connecting to `listener`, within the
+/// rate limits
+fn spawn_listener(
+    listener: TcpListener,
+    protocol: Protocol,
+    hs: Handshaker,
+    event_bus: mpsc::Sender<PeerManagerEvent>,
+) -> thread::JoinHandle<io::Result<()>> {
+    let failures = event_bus.clone();
+
+    accept_connections(
+        listener,
+        move |stream, remote, _pending_handshake| {
+            let event = match hs.server_handshake(stream, protocol, remote) {
+                Ok(peer_connection) => PeerManagerEvent {
+                    peer: Some(peer_connection.peer),
+                    event: PeerEvent::HandshakeSuccessful(Box::new(peer_connection)),
+                },
+                Err(err) => PeerManagerEvent {
+                    peer: None,
+                    event: PeerEvent::HandshakeFailed(
+                        PeerConnectionError::InboundHandshakeFailed {
+                            remote,
+                            source: Box::new(err),
+                        },
+                    ),
+                },
+            };
+            let _ = event_bus.send(event);
+        },
+        move |err| {
+            let _ = failures.send(PeerManagerEvent {
+                peer: None,
+                event: PeerEvent::HandshakeFailed(err),
+            });
+        },
+    )
+}
 
 impl PeerManager {
     pub fn new(
         ssb_public_key: PublicKey,
         ssb_secret_key: SecretKey,
         event_bus: mpsc::Sender<PeerManagerEvent>,
+        config: &NetworkConfig,
     ) -> PeerManager {
-        let handshaker = Handshaker::new(
-            event_bus.clone(),
-            ssb_public_key,
-            ssb_secret_key,
-            HANDSHAKE_NETWORK_KEY,
-        );
+        let handshaker = Handshaker::new(event_bus.clone(), ssb_public_key, ssb_secret_key, config);
 
         PeerManager {
             event_bus,
             handshake_listener: None,
+            websocket_listener: None,
             handshaker,
-            connections: Vec::new(),
+            listener_port: config.listener_port,
+            websocket_port: config.websocket_port,
+            public_key: ssb_public_key,
+            connections: HashMap::new(),
         }
     }
 
-    pub fn start_listener(&mut self) -> io::Result<()> {
-        let hs_listener_socket_addr = format!("0.0.0.0:{}", HANDSHAKE_LISTENER_PORT);
-        let listener = TcpListener::bind(hs_listener_socket_addr)?;
+    /// Takes ownership of a freshly handshaked connection and starts
+    /// reading from it if it's kept. When both peers dial each other at
+    /// once, both sides keep the connection dialed by the peer with the
+    /// lower public key and close the other one, so exactly one BoxStream
+    /// survives per feed. An existing connection the peer has been quiet
+    /// on for `STALE_CONNECTION` gives way to the new one whoever dialed.
+    pub fn register(&mut self, mut peer_connection: PeerConnection) -> Registration {
+        let feed_id = peer_connection.peer.feed_id();
 
-        let hs = self.handshaker.clone();
-        let event_bus = self.event_bus.clone();
+        let existing = match self.connections.get(&feed_id) {
+            Some(existing) => existing,
+            None => {
+                peer_connection.start_reading();
+                self.connections.insert(feed_id, peer_connection);
+                return Registration::New;
+            }
+        };
 
-        let listener_handle = thread::spawn(move || -> io::Result<()> {
-            for stream in listener.incoming() {
-                if let Ok(stream) = stream {
-                    if let Ok(peer_connection) = hs.server_handshake(stream) {
-                        event_bus.send(PeerManagerEvent {
-                            peer: peer_connection.peer,
-                            event: PeerEvent::HandshakeSuccessful(peer_connection),
-                        });
-                    }
-                }
+        let existing_dialer = existing.dialer(&self.public_key);
+        let new_dialer = peer_connection.dialer(&self.public_key);
+
+        if replaces(&new_dialer, &existing_dialer, existing.idle()) {
+            // dropping the old connection says goodbye on it
+            peer_connection.start_reading();
+            self.connections.insert(feed_id, peer_connection);
+            Registration::Replaced
+        } else {
+            Registration::Duplicate
+        }
+    }
+
+    /// Forgets a connection whose stream closed. Returns false if it wasn't
+    /// the peer's active connection, e.g. a duplicate we closed ourselves.
+    pub fn connection_closed(&mut self, feed_id: &str, connection_id: ConnectionId) -> bool {
+        match self.connections.get(feed_id) {
+            Some(connection) if connection.id == connection_id => {
+                self.connections.remove(feed_id);
+                true
             }
-            Ok(())
-        });
+            _ => false,
+        }
+    }
 
-        self.handshake_listener = Some(listener_handle);
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Starts connecting to the room at `address`, listing its attendants
+    /// on `network`
+    pub fn join_room(&self, address: String, network: String) -> RoomDiscovery {
+        room::join(
+            address,
+            self.handshaker.clone(),
+            self.event_bus.clone(),
+            self.public_key,
+            network,
+        )
+    }
+
+    pub fn connection(&self, feed_id: &str) -> Option<&PeerConnection> {
+        self.connections.get(feed_id)
+    }
+
+    pub fn connections(&self) -> impl Iterator<Item = &PeerConnection> {
+        self.connections.values()
+    }
+
+    pub fn is_connected(&self, feed_id: &str) -> bool {
+        self.connections.contains_key(feed_id)
+    }
+
+    pub fn send(&self, feed_id: &str, msg: String) -> Result<(), PeerConnectionError> {
+        self.connections
+            .get(feed_id)
+            .ok_or(PeerConnectionError::NotConnected {})?
+            .send(msg)
+    }
+
+    pub fn start_listener(&mut self) -> io::Result<()> {
+        let listener = bind_listener(self.listener_port)?;
+        self.handshake_listener = Some(spawn_listener(
+            listener,
+            Protocol::Net,
+            self.handshaker.clone(),
+            self.event_bus.clone(),
+        ));
+
+        if let Some(websocket_port) = self.websocket_port {
+            let listener = bind_listener(websocket_port)?;
+            self.websocket_listener = Some(spawn_listener(
+                listener,
+                Protocol::WebSocket,
+                self.handshaker.clone(),
+                self.event_bus.clone(),
+            ));
+        }
 
         Ok(())
     }
 
-    pub fn init_connection(&self, peer: PeerAddr) -> thread::JoinHandle<()> {
+    pub fn init_connection(&self, peer: PeerAddr, network: String) -> thread::JoinHandle<()> {
         let hs = self.handshaker.clone();
         let event_bus = self.event_bus.clone();
 
-        thread::spawn(move || match hs.client_handshake(peer) {
+        thread::spawn(move || match hs.client_handshake(peer, &network) {
             Ok(peer_connection) => {
-                event_bus.send(PeerManagerEvent {
-                    peer,
-                    event: PeerEvent::HandshakeSuccessful(peer_connection),
+                let _ = event_bus.send(PeerManagerEvent {
+                    peer: Some(peer),
+                    event: PeerEvent::HandshakeSuccessful(Box::new(peer_connection)),
                 });
             }
             Err(err) => {
-                event_bus.send(PeerManagerEvent {
-                    peer,
+                let _ = event_bus.send(PeerManagerEvent {
+                    peer: Some(peer),
                     event: PeerEvent::HandshakeFailed(err),
                 });
             }
         })
     }
+
+    /// Dials a peer like `init_connection`, but only reports a successful
+    /// handshake: a known peer that's no longer at its old address is no
+    /// news
+    pub fn probe(&self, peer: PeerAddr, network: String) -> thread::JoinHandle<()> {
+        let hs = self.handshaker.clone();
+        let event_bus = self.event_bus.clone();
+
+        thread::spawn(move || {
+            if let Ok(peer_connection) = hs.client_handshake(peer, &network) {
+                let _ = event_bus.send(PeerManagerEvent {
+                    peer: Some(peer),
+                    event: PeerEvent::HandshakeSuccessful(Box::new(peer_connection)),
+                });
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_counts_each_ip_on_its_own() {
+        let mut rate_limiter = RateLimiter::new();
+        let noisy = IpAddr::from([10, 0, 0, 1]);
+        let quiet = IpAddr::from([10, 0, 0, 2]);
+
+        for _ in 0..MAX_HANDSHAKES_PER_IP {
+            assert!(rate_limiter.allow(noisy));
+        }
+        assert!(!rate_limiter.allow(noisy));
+        assert!(rate_limiter.allow(quiet));
+    }
+
+    #[test]
+    fn rate_limiter_forgets_attempts_outside_the_window() {
+        let mut rate_limiter = RateLimiter::new();
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let expired = Instant::now() - RATE_LIMIT_WINDOW;
+        rate_limiter
+            .attempts
+            .insert(ip, vec![expired; MAX_HANDSHAKES_PER_IP]);
+
+        assert!(rate_limiter.allow(ip));
+        assert_eq!(rate_limiter.attempts[&ip].len(), 1);
+    }
+
+    #[test]
+    fn pending_handshakes_are_capped_and_freed_on_drop() {
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let slots: Vec<_> = (0..MAX_PENDING_HANDSHAKES)
+            .map(|_| PendingHandshake::start(&pending).unwrap())
+            .collect();
+        assert!(PendingHandshake::start(&pending).is_none());
+        assert_eq!(pending.load(Ordering::SeqCst), MAX_PENDING_HANDSHAKES);
+
+        drop(slots);
+        assert_eq!(pending.load(Ordering::SeqCst), 0);
+        assert!(PendingHandshake::start(&pending).is_some());
+    }
+
+    #[test]
+    fn the_lower_dialer_wins_a_simultaneous_dial() {
+        let low = PublicKey::from_slice(&[1; 32]).unwrap();
+        let high = PublicKey::from_slice(&[2; 32]).unwrap();
+
+        assert!(replaces(&low, &high, Duration::from_secs(0)));
+        assert!(!replaces(&high, &low, Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn a_reconnect_replaces_the_old_connection() {
+        let dialer = PublicKey::from_slice(&[1; 32]).unwrap();
+        assert!(replaces(&dialer, &dialer, Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn a_quiet_connection_gives_way_whoever_dialed() {
+        let low = PublicKey::from_slice(&[1; 32]).unwrap();
+        let high = PublicKey::from_slice(&[2; 32]).unwrap();
+
+        assert!(!replaces(&high, &low, STALE_CONNECTION / 2));
+        assert!(replaces(&high, &low, STALE_CONNECTION));
+    }
 }
@@ -0,0 +1,663 @@
+use crate::chat::FeedId;
+use crate::config::{PeersConfig, SortMode};
+use crate::discovery::{AnnouncementKind, PeerAddr, PeerAnnouncement};
+use crate::peer_connection::PeerConnection;
+use crate::peer_manager::HANDSHAKE_LISTENER_PORT;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// How we learned about a peer
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerSource {
+    /// Heard its discovery announcement
+    Discovery,
+    /// It connected to us without us hearing an announcement first
+    Inbound,
+    /// Heard its standard SSB announcement, which doesn't tell us whether
+    /// it runs scuttle-chat
+    Ssb,
+    /// The user gave us its address, it's kept until the app exits
+    Manual,
+    /// Its address is in the bootstrap list
+    Bootstrap,
+    /// Heard its stealth announcement, it's not to be told about
+    Stealth,
+    /// A connected peer told us about it, see `Peer::via`
+    Exchange,
+    /// It's in a room we're in, and only reached through the room
+    Room,
+}
+
+/// A peer we can start a chat with
+pub struct Peer {
+    pub addr: PeerAddr,
+    /// Name of the network the peer was found on, its key is used when
+    /// dialing the peer
+    pub network: String,
+    pub source: PeerSource,
+    /// When we last heard an announcement from the peer, or when it
+    /// connected to us
+    pub last_seen: Instant,
+    /// Offline peers stay listed as long as we have a chat with them
+    pub online: bool,
+    /// False for SSB peers until a chat connection with them succeeds
+    pub chat_capable: bool,
+    /// Whether the address was signed by the peer, or the peer proved
+    /// its key in a handshake
+    pub verified: bool,
+    /// When a message was last sent to or received from the peer
+    pub last_activity: Option<Instant>,
+    /// Messages received while the chat wasn't open
+    pub unread: usize,
+    /// The peer that told us about an exchanged peer
+    pub via: Option<FeedId>,
+}
+
+impl Peer {
+    pub fn inbound(peer_connection: &PeerConnection) -> Peer {
+        Peer {
+            addr: peer_connection.peer,
+            network: peer_connection.network.clone(),
+            source: PeerSource::Inbound,
+            last_seen: Instant::now(),
+            online: true,
+            chat_capable: true,
+            verified: true,
+            last_activity: None,
+            unread: 0,
+            via: None,
+        }
+    }
+
+    pub fn manual(addr: PeerAddr, network: String) -> Peer {
+        Peer {
+            addr,
+            network,
+            source: PeerSource::Manual,
+            last_seen: Instant::now(),
+            online: true,
+            chat_capable: true,
+            // the user vouches for the address, the handshake proves the
+            // key
+            verified: true,
+            last_activity: None,
+            unread: 0,
+            via: None,
+        }
+    }
+
+    /// A peer from the address book, offline until we hear from it
+    pub fn known(addr: PeerAddr, network: String, source: PeerSource) -> Peer {
+        Peer {
+            online: false,
+            source,
+            ..Peer::manual(addr, network)
+        }
+    }
+
+    /// Address to dial the peer on. Inbound peers connected to us from an
+    /// ephemeral port and SSB peers announce their SSB server's port, so
+    /// our best guess is the default listener port.
+    pub fn dial_addr(&self) -> PeerAddr {
+        match self.source {
+            PeerSource::Inbound | PeerSource::Ssb => {
+                let mut addr = self.addr;
+                addr.socket_addr.set_port(HANDSHAKE_LISTENER_PORT);
+                addr
+            }
+            PeerSource::Discovery
+            | PeerSource::Manual
+            | PeerSource::Bootstrap
+            | PeerSource::Stealth
+            | PeerSource::Exchange
+            | PeerSource::Room => self.addr,
+        }
+    }
+}
+
+impl From<PeerAnnouncement> for Peer {
+    fn from(announcement: PeerAnnouncement) -> Peer {
+        let (source, chat_capable) = match announcement.kind {
+            AnnouncementKind::Chat => (PeerSource::Discovery, true),
+            AnnouncementKind::Ssb => (PeerSource::Ssb, false),
+            AnnouncementKind::Bootstrap => (PeerSource::Bootstrap, true),
+            AnnouncementKind::Stealth => (PeerSource::Stealth, true),
+            // rooms are mostly attended by SSB clients
+            AnnouncementKind::Room => (PeerSource::Room, false),
+        };
+
+        Peer {
+            addr: announcement.peer,
+            network: announcement.network,
+            source,
+            last_seen: Instant::now(),
+            online: true,
+            chat_capable,
+            verified: announcement.verified,
+            last_activity: None,
+            unread: 0,
+            via: None,
+        }
+    }
+}
+
+/// The peers shown in the peer list, in display order. The selection is
+/// tracked by feed id, so it stays on the same peer as the list reorders.
+pub struct PeerList {
+    peers: HashMap<FeedId, Peer>,
+    selected: Option<FeedId>,
+    config: PeersConfig,
+    /// Only peers matching the filter are listed
+    filter: String,
+}
+
+impl PeerList {
+    pub fn new(config: PeersConfig) -> PeerList {
+        PeerList {
+            peers: HashMap::new(),
+            selected: None,
+            config,
+            filter: String::new(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: PeersConfig) {
+        self.config = config;
+    }
+
+    pub fn get(&self, feed_id: &str) -> Option<&Peer> {
+        self.peers.get(feed_id)
+    }
+
+    pub fn get_mut(&mut self, feed_id: &str) -> Option<&mut Peer> {
+        self.peers.get_mut(feed_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&FeedId, &Peer)> {
+        self.peers.iter()
+    }
+
+    /// Records an announcement, returns true if the peer wasn't online
+    /// before
+    pub fn announced(&mut self, announcement: PeerAnnouncement) -> bool {
+        let feed_id = announcement.peer.feed_id();
+        match self.peers.get_mut(&feed_id) {
+            // the peer's chat announcements know its chat port, which its
+            // SSB announcements don't
+            Some(peer)
+                if announcement.kind == AnnouncementKind::Ssb
+                    && (peer.source == PeerSource::Discovery
+                        || peer.source == PeerSource::Stealth)
+                    && peer.online =>
+            {
+                false
+            }
+            // a direct path beats a tunnel through a room
+            Some(peer)
+                if announcement.kind == AnnouncementKind::Room
+                    && peer.source != PeerSource::Room
+                    && peer.online =>
+            {
+                false
+            }
+            // anyone can announce a feed, so unsigned announcements don't
+            // get to move a peer we have a signed address for
+            Some(peer) if !announcement.verified && peer.verified && peer.online => false,
+            Some(peer) => {
+                let came_online = !peer.online;
+                let announced = Peer::from(announcement);
+                peer.addr = announced.addr;
+                peer.network = announced.network;
+                peer.source = announced.source;
+                peer.via = None;
+                peer.last_seen = announced.last_seen;
+                peer.online = true;
+                peer.chat_capable |= announced.chat_capable;
+                peer.verified = announced.verified;
+                came_online
+            }
+            None => {
+                self.peers.insert(feed_id, Peer::from(announcement));
+                true
+            }
+        }
+    }
+
+    /// Records a connection to the peer, adding peers that dialed us
+    /// without us hearing their announcement
+    pub fn connected(&mut self, peer_connection: &PeerConnection) {
+        let feed_id = peer_connection.peer.feed_id();
+        match self.peers.get_mut(&feed_id) {
+            Some(peer) => {
+                peer.last_seen = Instant::now();
+                peer.online = true;
+                peer.chat_capable = true;
+                peer.verified = true;
+            }
+            None => {
+                self.peers.insert(feed_id, Peer::inbound(peer_connection));
+            }
+        }
+    }
+
+    /// Lists a peer a connected peer told us about, or refreshes one it
+    /// told us about before. Peers we know first hand are left alone.
+    pub fn exchanged(&mut self, addr: PeerAddr, network: String, via: &str) {
+        match self.peers.get_mut(&addr.feed_id()) {
+            Some(peer) if peer.source == PeerSource::Exchange || !peer.online => {
+                peer.addr = addr;
+                peer.network = network;
+                peer.source = PeerSource::Exchange;
+                peer.via = Some(via.to_string());
+                peer.last_seen = Instant::now();
+                peer.online = true;
+                peer.verified = false;
+            }
+            Some(_) => {}
+            None => {
+                self.peers.insert(
+                    addr.feed_id(),
+                    Peer {
+                        source: PeerSource::Exchange,
+                        // only the handshake will tell whether the peer is
+                        // really at that address
+                        verified: false,
+                        via: Some(via.to_string()),
+                        ..Peer::manual(addr, network)
+                    },
+                );
+            }
+        }
+    }
+
+    /// Addresses of the online peers on `network` we know first hand and
+    /// may tell others about
+    pub fn shareable(&self, network: &str) -> Vec<PeerAddr> {
+        self.peers
+            .values()
+            .filter(|peer| peer.online && peer.verified && peer.network == network)
+            .filter(|peer| match peer.source {
+                PeerSource::Discovery
+                | PeerSource::Inbound
+                | PeerSource::Manual
+                | PeerSource::Bootstrap => true,
+                PeerSource::Ssb | PeerSource::Stealth | PeerSource::Exchange | PeerSource::Room => {
+                    false
+                }
+            })
+            .map(Peer::dial_addr)
+            .collect()
+    }
+
+    /// Lists a peer from the address book, unless it's listed already
+    pub fn known(&mut self, addr: PeerAddr, network: String, source: PeerSource) {
+        self.peers
+            .entry(addr.feed_id())
+            .or_insert_with(|| Peer::known(addr, network, source));
+    }
+
+    /// Adds a peer the user gave the address of, known peers are dialed
+    /// on that address from now on
+    pub fn added_manually(&mut self, addr: PeerAddr, network: String) {
+        let peer = Peer::manual(addr, network);
+        match self.peers.get_mut(&addr.feed_id()) {
+            Some(known) => {
+                known.addr = peer.addr;
+                known.network = peer.network;
+                known.source = peer.source;
+                known.verified = true;
+            }
+            None => {
+                self.peers.insert(addr.feed_id(), peer);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, feed_id: &str) {
+        self.peers.remove(feed_id);
+        if self.selected.as_deref() == Some(feed_id) {
+            self.selected = None;
+        }
+    }
+
+    /// Feed ids of the peers matching the filter in display order:
+    /// favourites, then online peers, then offline ones, each ordered by
+    /// the configured sort mode
+    pub fn ordered(&self) -> Vec<&FeedId> {
+        let mut peers = self
+            .peers
+            .iter()
+            .filter(|(feed_id, _)| self.matches_filter(feed_id))
+            .collect::<Vec<_>>();
+        peers.sort_by(|(a_id, a), (b_id, b)| {
+            self.is_favourite(b_id)
+                .cmp(&self.is_favourite(a_id))
+                .then(b.online.cmp(&a.online))
+                .then_with(|| self.compare(a_id, a, b_id, b))
+        });
+        peers.into_iter().map(|(feed_id, _)| feed_id).collect()
+    }
+
+    fn compare(&self, a_id: &str, a: &Peer, b_id: &str, b: &Peer) -> Ordering {
+        // peers with an alias before those only known by feed id
+        let alphabetical = || {
+            let key = |feed_id| {
+                let alias = self.alias(feed_id);
+                (alias.is_none(), alias.unwrap_or(feed_id).to_lowercase())
+            };
+            key(a_id).cmp(&key(b_id))
+        };
+        let recent = || Reverse(a.last_activity).cmp(&Reverse(b.last_activity));
+
+        match self.config.sort {
+            SortMode::Alphabetical => alphabetical(),
+            SortMode::Recent => recent().then_with(alphabetical),
+            SortMode::Unread => Reverse(a.unread)
+                .cmp(&Reverse(b.unread))
+                .then_with(recent)
+                .then_with(alphabetical),
+        }
+    }
+
+    pub fn selected(&self) -> Option<&FeedId> {
+        self.selected.as_ref()
+    }
+
+    /// Position of the selected peer in `ordered`
+    pub fn selected_index(&self) -> Option<usize> {
+        let selected = self.selected.as_ref()?;
+        self.ordered()
+            .iter()
+            .position(|feed_id| *feed_id == selected)
+    }
+
+    pub fn select(&mut self, feed_id: Option<FeedId>) {
+        self.selected = feed_id.filter(|feed_id| self.peers.contains_key(feed_id));
+    }
+
+    pub fn select_next(&mut self) {
+        let next = match self.selected_index() {
+            Some(idx) => idx + 1,
+            None => 0,
+        };
+        self.select_at(next);
+    }
+
+    pub fn select_prev(&mut self) {
+        let prev = match self.selected_index() {
+            Some(idx) if idx > 0 => idx - 1,
+            _ => self.ordered().len().saturating_sub(1),
+        };
+        self.select_at(prev);
+    }
+
+    /// Wraps around at the end of the list
+    fn select_at(&mut self, idx: usize) {
+        let ordered = self.ordered();
+        let feed_id = match ordered.len() {
+            0 => None,
+            len => Some(ordered[idx % len].clone()),
+        };
+        self.selected = feed_id;
+    }
+
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    /// Narrows the list, moving the selection to the first match if the
+    /// selected peer no longer matches
+    pub fn set_filter(&mut self, filter: String) {
+        self.filter = filter;
+
+        let selected_matches = match &self.selected {
+            Some(feed_id) => self.matches_filter(feed_id),
+            None => true,
+        };
+        if !selected_matches {
+            self.select_at(0);
+        }
+    }
+
+    /// Fuzzy matches the filter against the peer's alias and petnames, or
+    /// matches it as a prefix of the feed id
+    fn matches_filter(&self, feed_id: &str) -> bool {
+        let filter = self.filter.to_lowercase();
+        if filter.is_empty() {
+            return true;
+        }
+
+        let feed_id_matches = feed_id
+            .trim_start_matches('@')
+            .to_lowercase()
+            .starts_with(filter.trim_start_matches('@'));
+
+        feed_id_matches
+            || self
+                .alias(feed_id)
+                .into_iter()
+                .chain(self.petnames(feed_id))
+                .any(|name| fuzzy_match(&filter, &name.to_lowercase()))
+    }
+
+    pub fn alias(&self, feed_id: &str) -> Option<&str> {
+        self.config.aliases.get(feed_id).map(String::as_str)
+    }
+
+    /// Other names the user knows the peer by
+    pub fn petnames(&self, feed_id: &str) -> impl Iterator<Item = &str> {
+        self.config
+            .petnames
+            .get(feed_id)
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+    }
+
+    pub fn is_favourite(&self, feed_id: &str) -> bool {
+        self.config.favourites.iter().any(|fav| fav == feed_id)
+    }
+
+    /// Alias if the user gave the peer one, feed id otherwise
+    pub fn display_name(&self, feed_id: &str) -> String {
+        self.alias(feed_id).unwrap_or(feed_id).to_string()
+    }
+}
+
+/// Whether all characters of the query appear in the candidate, in order
+fn fuzzy_match(query: &str, candidate: &str) -> bool {
+    let mut candidate = candidate.chars();
+    query.chars().all(|q| candidate.any(|c| c == q))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::{AnnouncementKind, Protocol};
+    use ssb_crypto::PublicKey;
+    use std::time::Duration;
+
+    fn announce(peers: &mut PeerList, key_byte: u8) -> FeedId {
+        let peer = PeerAddr {
+            protocol: Protocol::Net,
+            socket_addr: ([10, 0, 0, key_byte], 8008).into(),
+            public_key: PublicKey::from_slice(&[key_byte; 32]).unwrap(),
+        };
+        peers.announced(PeerAnnouncement {
+            peer,
+            network: "main".to_string(),
+            kind: AnnouncementKind::Chat,
+            verified: true,
+        });
+        peer.feed_id()
+    }
+
+    fn ordered(peers: &PeerList) -> Vec<FeedId> {
+        peers.ordered().into_iter().cloned().collect()
+    }
+
+    #[test]
+    fn favourites_come_first_then_online_peers() {
+        let mut peers = PeerList::new(PeersConfig::default());
+        let a = announce(&mut peers, 1);
+        let b = announce(&mut peers, 2);
+        let c = announce(&mut peers, 3);
+        let mut alphabetical = [a, b, c];
+        alphabetical.sort_by_key(|feed_id| feed_id.to_lowercase());
+
+        peers.get_mut(&alphabetical[0]).unwrap().online = false;
+        peers.set_config(PeersConfig {
+            favourites: vec![alphabetical[2].clone()],
+            ..PeersConfig::default()
+        });
+
+        assert_eq!(
+            ordered(&peers),
+            vec![
+                alphabetical[2].clone(),
+                alphabetical[1].clone(),
+                alphabetical[0].clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn aliased_peers_sort_before_bare_feed_ids() {
+        let mut peers = PeerList::new(PeersConfig::default());
+        let a = announce(&mut peers, 1);
+        let b = announce(&mut peers, 2);
+        let c = announce(&mut peers, 3);
+
+        let mut aliases = HashMap::new();
+        aliases.insert(c.clone(), "alice".to_string());
+        aliases.insert(a.clone(), "Bob".to_string());
+        peers.set_config(PeersConfig {
+            aliases,
+            ..PeersConfig::default()
+        });
+
+        assert_eq!(ordered(&peers), vec![c.clone(), a, b]);
+        assert_eq!(peers.display_name(&c), "alice");
+    }
+
+    #[test]
+    fn recent_and_unread_modes_order_by_activity() {
+        let mut peers = PeerList::new(PeersConfig::default());
+        let a = announce(&mut peers, 1);
+        let b = announce(&mut peers, 2);
+        let c = announce(&mut peers, 3);
+
+        let now = Instant::now();
+        peers.get_mut(&a).unwrap().last_activity = Some(now - Duration::from_secs(60));
+        peers.get_mut(&b).unwrap().last_activity = Some(now);
+        peers.get_mut(&a).unwrap().unread = 3;
+        peers.get_mut(&c).unwrap().unread = 1;
+
+        peers.set_config(PeersConfig {
+            sort: SortMode::Recent,
+            ..PeersConfig::default()
+        });
+        assert_eq!(ordered(&peers), vec![b.clone(), a.clone(), c.clone()]);
+
+        peers.set_config(PeersConfig {
+            sort: SortMode::Unread,
+            ..PeersConfig::default()
+        });
+        assert_eq!(ordered(&peers), vec![a, c, b]);
+    }
+
+    #[test]
+    fn the_selection_follows_its_peer_as_the_list_reorders() {
+        let mut peers = PeerList::new(PeersConfig::default());
+        let a = announce(&mut peers, 1);
+        let b = announce(&mut peers, 2);
+
+        peers.select(Some(a.clone()));
+        let first = ordered(&peers)[0].clone();
+        let other = if first == a { b } else { a.clone() };
+        // an alias moves the other peer to the top
+        let mut aliases = HashMap::new();
+        aliases.insert(other.clone(), "zed".to_string());
+        peers.set_config(PeersConfig {
+            aliases,
+            ..PeersConfig::default()
+        });
+
+        assert_eq!(ordered(&peers)[0], other);
+        assert_eq!(peers.selected(), Some(&a));
+        assert_eq!(ordered(&peers)[peers.selected_index().unwrap()], a);
+    }
+
+    #[test]
+    fn selection_wraps_and_is_cleared_with_its_peer() {
+        let mut peers = PeerList::new(PeersConfig::default());
+        announce(&mut peers, 1);
+        announce(&mut peers, 2);
+        let order = ordered(&peers);
+
+        peers.select_next();
+        assert_eq!(peers.selected(), Some(&order[0]));
+        peers.select_prev();
+        assert_eq!(peers.selected(), Some(&order[1]));
+        peers.select_next();
+        assert_eq!(peers.selected(), Some(&order[0]));
+
+        peers.select(Some("@unknown.ed25519".to_string()));
+        assert_eq!(peers.selected(), None);
+
+        peers.select(Some(order[1].clone()));
+        peers.remove(&order[1]);
+        assert_eq!(peers.selected(), None);
+    }
+
+    #[test]
+    fn the_filter_matches_names_fuzzily_and_feed_ids_by_prefix() {
+        let mut peers = PeerList::new(PeersConfig::default());
+        let a = announce(&mut peers, 1);
+        let b = announce(&mut peers, 2);
+        let c = announce(&mut peers, 3);
+
+        let mut config = PeersConfig::default();
+        config.aliases.insert(a.clone(), "Alice".to_string());
+        config
+            .petnames
+            .insert(b.clone(), vec!["bobby tables".to_string()]);
+        peers.set_config(config);
+
+        peers.set_filter("ace".to_string());
+        assert_eq!(ordered(&peers), vec![a.clone()]);
+        peers.set_filter("btab".to_string());
+        assert_eq!(ordered(&peers), vec![b.clone()]);
+        // feed ids only match from the start, with or without the sigil
+        peers.set_filter(c[..6].to_string());
+        assert_eq!(ordered(&peers), vec![c.clone()]);
+        peers.set_filter(c[1..6].to_lowercase());
+        assert_eq!(ordered(&peers), vec![c.clone()]);
+        peers.set_filter(c[2..8].to_string());
+        assert!(ordered(&peers).is_empty());
+    }
+
+    #[test]
+    fn filtering_moves_the_selection_onto_a_match() {
+        let mut peers = PeerList::new(PeersConfig::default());
+        let a = announce(&mut peers, 1);
+        let b = announce(&mut peers, 2);
+
+        let mut config = PeersConfig::default();
+        config.aliases.insert(b.clone(), "bob".to_string());
+        peers.set_config(config);
+
+        peers.select(Some(a));
+        peers.set_filter("bob".to_string());
+        assert_eq!(peers.selected(), Some(&b));
+
+        // j and k stay within the matches
+        peers.select_next();
+        assert_eq!(peers.selected(), Some(&b));
+        peers.select_prev();
+        assert_eq!(peers.selected(), Some(&b));
+    }
+}
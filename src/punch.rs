@@ -0,0 +1,52 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long both sides keep dialing each other
+pub const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Timeout of a single dial, SYNs a NAT drops are never answered
+const ATTEMPT_TIMEOUT: Duration = Duration::from_millis(500);
+const ATTEMPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A TCP socket whose local port other sockets can bind to as well
+fn reusable_socket(addr: &SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    Ok(socket)
+}
+
+/// Dials `remote` from a port `connect` can dial from again, so a room
+/// connection's NAT mapping, which the room tells other attendants about,
+/// can be reused to punch through.
+pub fn dial(remote: &SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+    let socket = reusable_socket(remote)?;
+    socket.connect_timeout(&(*remote).into(), timeout)?;
+    Ok(socket.into())
+}
+
+/// Simultaneous open: dials `remote` from `local`, the address of our room
+/// connection, until `PUNCH_TIMEOUT` passes. The peer does the same the
+/// other way round, so each side's SYN opens its NAT for the other's. Only
+/// works behind NATs that map a local port to the same public port
+/// whatever the destination.
+pub fn connect(local: SocketAddr, remote: SocketAddr) -> io::Result<TcpStream> {
+    let deadline = Instant::now() + PUNCH_TIMEOUT;
+
+    loop {
+        let socket = reusable_socket(&remote)?;
+        socket.bind(&local.into())?;
+
+        match socket.connect_timeout(&remote.into(), ATTEMPT_TIMEOUT) {
+            Ok(()) => return Ok(socket.into()),
+            Err(err) if Instant::now() + ATTEMPT_INTERVAL >= deadline => return Err(err),
+            Err(_) => thread::sleep(ATTEMPT_INTERVAL),
+        }
+    }
+}
@@ -0,0 +1,524 @@
+use crate::box_stream::{BoxReader, BoxWriter};
+use crate::chat::FeedId;
+use crate::config::Config;
+use crate::muxrpc::{self, Packet, PacketError, PacketReader};
+use crate::peer_connection::{Handshaker, PeerConnectionError};
+use crate::peer_manager::{accept_connections, bind_listener, PeerManagerEvent, PendingHandshake};
+use crate::ssb::SsbConfig;
+use serde_json::{json, Value};
+use snafu::ResultExt;
+use ssb_crypto::PublicKey;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The port SSB rooms usually take connections on
+pub const RELAY_PORT: u16 = 8008;
+
+#[derive(Snafu, Debug)]
+pub enum RelayError {
+    #[snafu(display("Failed to listen on port {}: {}", port, source))]
+    ListenFailed { port: u16, source: io::Error },
+    #[snafu(display("Handshake with {} failed: {}", remote, source))]
+    HandshakeFailed {
+        remote: SocketAddr,
+        source: PeerConnectionError,
+    },
+    #[snafu(display("Refused {}, it's not in the allowed feeds", feed_id))]
+    NotAllowed { feed_id: FeedId },
+    #[snafu(display("Failed to clone client stream: {}", source))]
+    StreamCloneFailed { source: io::Error },
+    #[snafu(display("Lost {}: {}", feed_id, source))]
+    ReadFailed {
+        feed_id: FeedId,
+        source: PacketError,
+    },
+}
+
+fn feed_id(public_key: &PublicKey) -> FeedId {
+    format!("@{}.ed25519", base64::encode(&public_key.0))
+}
+
+type SessionId = u64;
+
+/// A client's connection to the relay
+struct Session {
+    feed_id: FeedId,
+    writer: mpsc::Sender<Packet>,
+    /// Where we see the client connect from, its NAT's public side
+    remote: SocketAddr,
+    /// Number of our next request to the client
+    next_req: i32,
+}
+
+/// Who's attending, and how their tunnels are spliced together
+#[derive(Default)]
+struct Attendance {
+    next_session: SessionId,
+    sessions: HashMap<SessionId, Session>,
+    /// The latest session of each attendant
+    attendants: HashMap<FeedId, SessionId>,
+    /// `room.attendants` streams to tell about comings and goings
+    subscriptions: Vec<(SessionId, i32)>,
+    /// Packets a session sends on a request number, and where they're
+    /// passed on to, as they are
+    splices: HashMap<(SessionId, i32), (SessionId, i32)>,
+}
+
+impl Attendance {
+    fn send(&self, session: SessionId, packet: Packet) {
+        if let Some(session) = self.sessions.get(&session) {
+            let _ = session.writer.send(packet);
+        }
+    }
+
+    fn notify(&self, event: &Value) {
+        for (session, req) in self.subscriptions.iter() {
+            self.send(*session, Packet::value(-req, true, event));
+        }
+    }
+
+    fn join(
+        &mut self,
+        feed_id: FeedId,
+        writer: mpsc::Sender<Packet>,
+        remote: SocketAddr,
+    ) -> SessionId {
+        let id = self.next_session;
+        self.next_session += 1;
+
+        self.sessions.insert(
+            id,
+            Session {
+                feed_id: feed_id.clone(),
+                writer,
+                remote,
+                next_req: 1,
+            },
+        );
+        // a reconnecting client takes over from its old session, which
+        // winds down on its own
+        if self.attendants.insert(feed_id.clone(), id).is_none() {
+            self.notify(&json!({ "type": "joined", "id": feed_id }));
+        }
+        id
+    }
+
+    /// Forgets the session and ends the other halves of its tunnels
+    fn leave(&mut self, id: SessionId) {
+        let session = match self.sessions.remove(&id) {
+            Some(session) => session,
+            None => return,
+        };
+        if self.attendants.get(&session.feed_id) == Some(&id) {
+            self.attendants.remove(&session.feed_id);
+            self.notify(&json!({ "type": "left", "id": session.feed_id }));
+        }
+        self.subscriptions.retain(|(session, _)| *session != id);
+
+        let orphaned = self
+            .splices
+            .iter()
+            .filter(|((from, _), _)| *from == id)
+            .map(|(_, to)| *to)
+            .collect::<Vec<_>>();
+        self.splices
+            .retain(|(from, _), (to, _)| *from != id && *to != id);
+        for (to, req) in orphaned {
+            self.send(to, Packet::end(req));
+        }
+    }
+
+    /// Passes a tunnel packet on to the other end, returns false for
+    /// packets of no tunnel
+    fn forward(&mut self, from: SessionId, packet: &Packet) -> bool {
+        let (to, req) = match self.splices.get(&(from, packet.req)) {
+            Some(to) => *to,
+            None => return false,
+        };
+        // an async answer is the only packet on its request number
+        if packet.end_or_error || !packet.stream {
+            self.splices.remove(&(from, packet.req));
+        }
+        self.send(
+            to,
+            Packet {
+                req,
+                ..packet.clone()
+            },
+        );
+        true
+    }
+
+    /// Calls `tunnel.connect` on the target and splices its stream with
+    /// the origin's
+    fn connect(
+        &mut self,
+        from: SessionId,
+        req: i32,
+        portal: &str,
+        target: &str,
+    ) -> Result<(), &'static str> {
+        let origin = self
+            .sessions
+            .get(&from)
+            .ok_or("not attending")?
+            .feed_id
+            .clone();
+        let to = *self
+            .attendants
+            .get(target)
+            .ok_or("target isn't attending")?;
+        let session = self.sessions.get_mut(&to).ok_or("target isn't attending")?;
+        let to_req = session.next_req;
+        session.next_req += 1;
+
+        self.send(
+            to,
+            Packet::request(
+                to_req,
+                &["tunnel", "connect"],
+                "duplex",
+                json!([{ "origin": origin, "portal": portal, "target": target }]),
+            ),
+        );
+        // the origin's packets on `req` go to the target on `to_req`, the
+        // target's answers on `-to_req` back to the origin on `-req`
+        self.splices.insert((from, req), (to, to_req));
+        self.splices.insert((to, -to_req), (from, -req));
+        Ok(())
+    }
+
+    /// Calls `punch.connect` on the target with the public addresses we
+    /// see the two connect from, its answer goes back to the origin as is
+    fn punch(&mut self, from: SessionId, req: i32, target: &str) -> Result<(), &'static str> {
+        let origin = self.sessions.get(&from).ok_or("not attending")?;
+        let (origin_id, endpoint) = (origin.feed_id.clone(), origin.remote);
+        let to = *self
+            .attendants
+            .get(target)
+            .ok_or("target isn't attending")?;
+        let session = self.sessions.get_mut(&to).ok_or("target isn't attending")?;
+        let to_req = session.next_req;
+        session.next_req += 1;
+        let observed = session.remote;
+
+        self.send(
+            to,
+            Packet::request(
+                to_req,
+                &["punch", "connect"],
+                "async",
+                json!([{
+                    "origin": origin_id,
+                    "endpoint": endpoint.to_string(),
+                    "observed": observed.to_string(),
+                }]),
+            ),
+        );
+        self.splices.insert((to, -to_req), (from, -req));
+        Ok(())
+    }
+}
+
+/// A minimal room: attendants can see each other and tunnel, or punch
+/// through their NATs, to each other. The relay only splices tunnels, the
+/// secret handshake and box stream inside them run between the
+/// attendants, so it can't read their chats.
+struct Relay {
+    handshaker: Handshaker,
+    feed_id: FeedId,
+    /// Feeds that may attend, anyone may if it's empty
+    allowed: Vec<FeedId>,
+    attendance: Mutex<Attendance>,
+}
+
+impl Relay {
+    fn new(handshaker: Handshaker, public_key: &PublicKey, allowed: Vec<FeedId>) -> Relay {
+        Relay {
+            handshaker,
+            feed_id: feed_id(public_key),
+            allowed,
+            attendance: Mutex::new(Attendance::default()),
+        }
+    }
+
+    fn answer(&self, id: SessionId, packet: &Packet) {
+        let mut attendance = self.attendance.lock().unwrap();
+        if attendance.forward(id, packet) || packet.req < 0 {
+            return;
+        }
+        if packet.end_or_error {
+            // the client is done with its attendants stream
+            attendance
+                .subscriptions
+                .retain(|subscription| *subscription != (id, packet.req));
+            return;
+        }
+
+        let reply = match packet.call() {
+            Some((ref name, _)) if name == "tunnel.isRoom" => Packet::value(
+                -packet.req,
+                false,
+                &json!({
+                    "name": "scuttle-chat relay",
+                    "membership": false,
+                    "features": ["tunnel", "room2"],
+                }),
+            ),
+            // everyone connected attends
+            Some((ref name, _)) if name == "tunnel.announce" || name == "tunnel.leave" => {
+                Packet::value(-packet.req, false, &json!(true))
+            }
+            Some((ref name, _)) if name == "tunnel.ping" => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|time| time.as_millis() as u64)
+                    .unwrap_or(0);
+                Packet::value(-packet.req, false, &json!(now))
+            }
+            Some((ref name, _)) if name == "room.attendants" && packet.stream => {
+                attendance.subscriptions.push((id, packet.req));
+                let ids = attendance.attendants.keys().collect::<Vec<_>>();
+                Packet::value(-packet.req, true, &json!({ "type": "state", "ids": ids }))
+            }
+            Some((ref name, ref args)) if name == "tunnel.connect" && packet.stream => {
+                let target = args.first().and_then(|arg| arg["target"].as_str());
+                match target.map(|target| attendance.connect(id, packet.req, &self.feed_id, target))
+                {
+                    Some(Ok(())) => return,
+                    Some(Err(reason)) => Packet::error(-packet.req, true, reason),
+                    None => Packet::error(-packet.req, true, "tunnel.connect needs a target"),
+                }
+            }
+            Some((ref name, ref args)) if name == "punch.connect" && !packet.stream => {
+                let target = args.first().and_then(|arg| arg["target"].as_str());
+                match target.map(|target| attendance.punch(id, packet.req, target)) {
+                    Some(Ok(())) => return,
+                    Some(Err(reason)) => Packet::error(-packet.req, false, reason),
+                    None => Packet::error(-packet.req, false, "punch.connect needs a target"),
+                }
+            }
+            _ => Packet::error(-packet.req, packet.stream, "method not supported"),
+        };
+        attendance.send(id, reply);
+    }
+
+    /// Speaks muxrpc with a client until it disconnects. The client only
+    /// counts as a pending handshake until it's handshaked and let in,
+    /// which `HANDSHAKE_TIMEOUT` bounds.
+    fn serve(
+        &self,
+        mut stream: TcpStream,
+        remote: SocketAddr,
+        pending_handshake: PendingHandshake,
+    ) -> Result<(), RelayError> {
+        let (client_pk, keys) = self
+            .handshaker
+            .relay_handshake(&mut stream)
+            .context(HandshakeFailed { remote })?;
+        let feed_id = feed_id(&client_pk);
+        if !self.allowed.is_empty() && !self.allowed.contains(&feed_id) {
+            return Err(RelayError::NotAllowed { feed_id });
+        }
+        drop(pending_handshake);
+
+        let mut box_writer = BoxWriter::new(
+            stream.try_clone().context(StreamCloneFailed)?,
+            keys.write_key,
+            keys.write_noncegen,
+        );
+        let mut reader = PacketReader::new(BoxReader::new(
+            stream.try_clone().context(StreamCloneFailed)?,
+            keys.read_key,
+            keys.read_noncegen,
+        ));
+
+        let (writer, packets) = mpsc::channel::<Packet>();
+        thread::spawn(move || {
+            while let Ok(packet) = packets.recv() {
+                if muxrpc::send(&mut box_writer, &packet).is_err() {
+                    return;
+                }
+            }
+            let _ = muxrpc::send_goodbye(box_writer);
+        });
+
+        let id = self
+            .attendance
+            .lock()
+            .unwrap()
+            .join(feed_id.clone(), writer, remote);
+        println!("{} joined", feed_id);
+
+        let result = loop {
+            match reader.recv() {
+                Ok(Some(packet)) => self.answer(id, &packet),
+                Ok(None) => break Ok(()),
+                Err(err) => {
+                    break Err(err).context(ReadFailed {
+                        feed_id: feed_id.clone(),
+                    })
+                }
+            }
+        };
+
+        self.attendance.lock().unwrap().leave(id);
+        let _ = stream.shutdown(Shutdown::Both);
+        println!("{} left", feed_id);
+        result
+    }
+}
+
+/// Serves every client connecting to `listener`, each on a thread of its
+/// own
+fn listen(relay: Arc<Relay>, listener: TcpListener) -> thread::JoinHandle<io::Result<()>> {
+    accept_connections(
+        listener,
+        move |stream, remote, pending_handshake| {
+            if let Err(err) = relay.serve(stream, remote, pending_handshake) {
+                eprintln!("{}", err);
+            }
+        },
+        |err| eprintln!("{}", err),
+    )
+}
+
+/// Runs `scuttle-chat relay` until the process is killed, logging who
+/// joins and leaves to stdout and errors to stderr
+pub fn run(config: &Config) -> Result<(), RelayError> {
+    let ssb_config = SsbConfig::default();
+    let (pk, sk) = ssb_config.keys();
+
+    // relay sessions aren't chats, nothing goes out on the event bus
+    let (event_bus, _) = mpsc::channel::<PeerManagerEvent>();
+    let handshaker = Handshaker::new(event_bus, *pk, sk.clone(), &config.network);
+    let relay = Arc::new(Relay::new(handshaker, pk, config.relay.allowed.clone()));
+
+    let port = config.relay.port;
+    let listener = bind_listener(port).context(ListenFailed { port })?;
+    println!(
+        "Relaying on port {} as {}, dial net:<host>:{}~shs:{}",
+        port,
+        relay.feed_id,
+        port,
+        base64::encode(&pk.0)
+    );
+
+    let _ = listen(relay, listener).join();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetworkConfig;
+    use crate::discovery::{Discovery, Protocol};
+    use crate::peer_manager::PeerEvent;
+    use crate::room::{self, RoomDiscovery};
+    use ssb_crypto::generate_longterm_keypair;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    struct Attendant {
+        public_key: PublicKey,
+        handshaker: Handshaker,
+        events: mpsc::Receiver<PeerManagerEvent>,
+        discovery: RoomDiscovery,
+    }
+
+    /// Waits for the first event `matches` picks out
+    fn wait_for<T>(
+        events: &mpsc::Receiver<PeerManagerEvent>,
+        matches: impl Fn(PeerEvent) -> Option<T>,
+    ) -> T {
+        loop {
+            let event = events.recv_timeout(TIMEOUT).expect("no event in time");
+            if let Some(found) = matches(event.event) {
+                return found;
+            }
+        }
+    }
+
+    fn attend(address: &str) -> Attendant {
+        let (public_key, secret_key) = generate_longterm_keypair();
+        let (event_bus, events) = mpsc::channel();
+        let handshaker = Handshaker::new(
+            event_bus.clone(),
+            public_key,
+            secret_key,
+            &NetworkConfig::default(),
+        );
+        let discovery = room::join(
+            address.to_string(),
+            handshaker.clone(),
+            event_bus,
+            public_key,
+            "main".to_string(),
+        );
+
+        wait_for(&events, |event| match event {
+            PeerEvent::RoomJoined => Some(()),
+            PeerEvent::RoomLeft(_, err) => panic!("failed to join: {}", err),
+            _ => None,
+        });
+        Attendant {
+            public_key,
+            handshaker,
+            events,
+            discovery,
+        }
+    }
+
+    #[test]
+    fn attendants_tunnel_through_the_relay() {
+        let (pk, sk) = generate_longterm_keypair();
+        let (event_bus, _events) = mpsc::channel();
+        let handshaker = Handshaker::new(event_bus, pk, sk, &NetworkConfig::default());
+        let relay = Arc::new(Relay::new(handshaker, &pk, Vec::new()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!(
+            "net:{}~shs:{}",
+            listener.local_addr().unwrap(),
+            base64::encode(&pk.0)
+        );
+        listen(relay, listener);
+
+        let mut alice = attend(&address);
+        let bob = attend(&address);
+
+        let announcement = loop {
+            let announcement = alice.discovery.recv().unwrap();
+            if announcement.peer.public_key == bob.public_key {
+                break announcement;
+            }
+        };
+        match announcement.peer.protocol {
+            Protocol::Tunnel(portal) => assert_eq!(portal, pk),
+            _ => panic!("attendants are reached through the relay"),
+        }
+
+        let to_bob = alice
+            .handshaker
+            .client_handshake(announcement.peer, "main")
+            .unwrap();
+        let mut from_alice = wait_for(&bob.events, |event| match event {
+            PeerEvent::HandshakeSuccessful(connection) => Some(connection),
+            _ => None,
+        });
+        assert_eq!(from_alice.peer.public_key, alice.public_key);
+
+        from_alice.start_reading();
+        to_bob.send("hello through the relay".to_string()).unwrap();
+        let msg = wait_for(&bob.events, |event| match event {
+            PeerEvent::MessageReceived(msg) => Some(msg),
+            _ => None,
+        });
+        assert_eq!(msg, "hello through the relay");
+    }
+}
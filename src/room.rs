@@ -0,0 +1,593 @@
+use crate::box_stream::{BoxReader, BoxWriter};
+use crate::chat::FeedId;
+use crate::discovery::{
+    parse_feed_id, AnnouncementKind, Discovery, DiscoveryServiceError, ParsePeerAddrError,
+    PeerAddr, PeerAnnouncement, Protocol, ANNOUNCE_INTERVAL,
+};
+use crate::muxrpc::{self, Packet, PacketError, PacketReader};
+use crate::peer_connection::{Handshaker, PeerConnectionError, Transport};
+use crate::peer_manager::{PeerEvent, PeerManagerEvent};
+use crate::punch::{self, PUNCH_TIMEOUT};
+use serde_json::{json, Value};
+use snafu::ResultExt;
+use ssb_crypto::PublicKey;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long to wait before dialing a room again after losing it
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Snafu, Debug)]
+pub enum RoomError {
+    #[snafu(display("Invalid room address: {}", source))]
+    InvalidAddress { source: ParsePeerAddrError },
+    #[snafu(display("Failed to connect to room: {}", source))]
+    ConnectFailed { source: PeerConnectionError },
+    #[snafu(display("Failed to clone room stream: {}", source))]
+    StreamCloneFailed { source: io::Error },
+    #[snafu(display("Failed to read from room: {}", source))]
+    ReadFailed { source: PacketError },
+    #[snafu(display("Room closed the connection"))]
+    Closed {},
+}
+
+fn feed_id(public_key: &PublicKey) -> FeedId {
+    format!("@{}.ed25519", base64::encode(&public_key.0))
+}
+
+/// The open tunnels of a room connection, by the request number our
+/// packets for them carry: positive for tunnels we opened, negative for
+/// the ones the room opened to us
+struct Tunnels {
+    next_req: i32,
+    streams: HashMap<i32, mpsc::Sender<Vec<u8>>>,
+    /// Async calls we're waiting on the answer of
+    calls: HashMap<i32, mpsc::Sender<Packet>>,
+}
+
+/// Sends muxrpc packets to a room and keeps track of the tunnels through
+/// it
+#[derive(Clone)]
+struct RoomHandle {
+    writer: mpsc::Sender<Packet>,
+    tunnels: Arc<Mutex<Tunnels>>,
+    /// Our end of the room connection, which punched connections dial
+    /// from, unless we don't punch through this room
+    local_addr: Option<SocketAddr>,
+}
+
+impl RoomHandle {
+    fn next_req(&self) -> i32 {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let req = tunnels.next_req;
+        tunnels.next_req += 1;
+        req
+    }
+
+    fn request(&self, name: &[&str], kind: &str, args: Value) -> i32 {
+        let req = self.next_req();
+        let _ = self.writer.send(Packet::request(req, name, kind, args));
+        req
+    }
+
+    /// Calls an async method, the answer is passed to the returned
+    /// receiver
+    fn call(&self, name: &[&str], args: Value) -> (i32, mpsc::Receiver<Packet>) {
+        let (tx, rx) = mpsc::channel();
+        let req = self.next_req();
+        self.tunnels.lock().unwrap().calls.insert(req, tx);
+        let _ = self.writer.send(Packet::request(req, name, "async", args));
+        (req, rx)
+    }
+
+    /// Starts passing the data of stream `req` to a new tunnel
+    fn tunnel(&self, req: i32) -> TunnelStream {
+        let (tx, rx) = mpsc::channel();
+        self.tunnels.lock().unwrap().streams.insert(req, tx);
+
+        TunnelStream {
+            req,
+            incoming: Arc::new(Mutex::new(Incoming {
+                rx,
+                buf: Vec::new(),
+            })),
+            read_timeout: Arc::new(Mutex::new(None)),
+            ended: Arc::new(AtomicBool::new(false)),
+            writer: self.writer.clone(),
+            tunnels: self.tunnels.clone(),
+        }
+    }
+
+    /// Passes a packet on to its tunnel, returns false for packets of no
+    /// tunnel
+    fn route(&self, packet: &Packet) -> bool {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let req = -packet.req;
+
+        if !packet.stream {
+            return match tunnels.calls.remove(&req) {
+                Some(tx) => {
+                    let _ = tx.send(packet.clone());
+                    true
+                }
+                None => false,
+            };
+        }
+        if packet.end_or_error {
+            // dropping the sender ends the tunnel's reads
+            return tunnels.streams.remove(&req).is_some();
+        }
+        match tunnels.streams.get(&req) {
+            Some(tx) => {
+                let _ = tx.send(packet.body.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ends the reads of every tunnel, and the waits for answers
+    fn close(&self) {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        tunnels.streams.clear();
+        tunnels.calls.clear();
+    }
+}
+
+/// The rooms we're connected to, by the room's feed id
+#[derive(Clone, Default)]
+pub struct Rooms(Arc<Mutex<HashMap<FeedId, RoomHandle>>>);
+
+impl Rooms {
+    /// Asks the room with key `portal` for a tunnel to `target`, whose
+    /// secret handshake and box stream run end to end through the room
+    pub fn open(&self, portal: &PublicKey, target: &PublicKey) -> Option<TunnelStream> {
+        let rooms = self.0.lock().unwrap();
+        let room = rooms.get(&feed_id(portal))?;
+
+        let req = room.next_req();
+        let tunnel = room.tunnel(req);
+        room.writer
+            .send(Packet::request(
+                req,
+                &["tunnel", "connect"],
+                "duplex",
+                json!([{ "portal": feed_id(portal), "target": feed_id(target) }]),
+            ))
+            .ok()?;
+        Some(tunnel)
+    }
+
+    /// Asks the room with key `portal` to tell `target` and us each
+    /// other's public address, then punches through to it. `None` if the
+    /// room or the target don't punch, or no connection got through.
+    pub fn punch(&self, portal: &PublicKey, target: &PublicKey) -> Option<TcpStream> {
+        let room = self.0.lock().unwrap().get(&feed_id(portal))?.clone();
+        let local_addr = room.local_addr?;
+
+        let (req, answer) = room.call(
+            &["punch", "connect"],
+            json!([{ "portal": feed_id(portal), "target": feed_id(target) }]),
+        );
+        let answer = answer.recv_timeout(PUNCH_TIMEOUT).ok();
+        room.tunnels.lock().unwrap().calls.remove(&req);
+
+        let endpoint = answer
+            .filter(|answer| !answer.end_or_error)
+            .and_then(|answer| answer.json())
+            .and_then(|answer| answer["endpoint"].as_str()?.parse().ok())?;
+        punch::connect(local_addr, endpoint).ok()
+    }
+
+    fn insert(&self, room: FeedId, handle: RoomHandle) {
+        self.0.lock().unwrap().insert(room, handle);
+    }
+
+    fn remove(&self, room: &str) {
+        self.0.lock().unwrap().remove(room);
+    }
+}
+
+struct Incoming {
+    rx: mpsc::Receiver<Vec<u8>>,
+    /// Rest of the last chunk read
+    buf: Vec<u8>,
+}
+
+/// A duplex muxrpc stream through a room, carrying the bytes of a secret
+/// handshake and box stream the room can't read
+pub struct TunnelStream {
+    req: i32,
+    incoming: Arc<Mutex<Incoming>>,
+    read_timeout: Arc<Mutex<Option<Duration>>>,
+    /// Whether we ended our side of the stream
+    ended: Arc<AtomicBool>,
+    writer: mpsc::Sender<Packet>,
+    tunnels: Arc<Mutex<Tunnels>>,
+}
+
+impl Read for TunnelStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let mut incoming = self.incoming.lock().unwrap();
+
+        if incoming.buf.is_empty() {
+            let chunk = match timeout {
+                Some(timeout) => incoming.rx.recv_timeout(timeout).map_err(|e| match e {
+                    RecvTimeoutError::Timeout => Some(io::ErrorKind::TimedOut),
+                    RecvTimeoutError::Disconnected => None,
+                }),
+                None => incoming.rx.recv().map_err(|_| None),
+            };
+            incoming.buf = match chunk {
+                Ok(chunk) => chunk,
+                Err(Some(kind)) => return Err(io::Error::from(kind)),
+                // the stream ended
+                Err(None) => return Ok(0),
+            };
+        }
+
+        let len = buf.len().min(incoming.buf.len());
+        buf[..len].copy_from_slice(&incoming.buf[..len]);
+        incoming.buf.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for TunnelStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer
+            .send(Packet::data(self.req, buf.to_vec()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for TunnelStream {
+    fn try_clone(&self) -> io::Result<TunnelStream> {
+        Ok(TunnelStream {
+            req: self.req,
+            incoming: self.incoming.clone(),
+            read_timeout: self.read_timeout.clone(),
+            ended: self.ended.clone(),
+            writer: self.writer.clone(),
+            tunnels: self.tunnels.clone(),
+        })
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.tunnels.lock().unwrap().streams.remove(&self.req);
+        if !self.ended.swap(true, Ordering::SeqCst) {
+            let _ = self.writer.send(Packet::end(self.req));
+        }
+        Ok(())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    /// Writes only queue packets for the room connection, they never
+    /// block
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Connects to the room at `address` and keeps reconnecting, passing the
+/// room's attendants to the returned discovery. Chats with attendants are
+/// tunnelled through the room.
+pub fn join(
+    address: String,
+    handshaker: Handshaker,
+    event_bus: mpsc::Sender<PeerManagerEvent>,
+    public_key: PublicKey,
+    network: String,
+) -> RoomDiscovery {
+    let (attendants_tx, attendants_rx) = mpsc::channel();
+
+    thread::spawn(move || loop {
+        let result = run(
+            &address,
+            &handshaker,
+            &event_bus,
+            public_key,
+            &attendants_tx,
+        );
+        let _ = attendants_tx.send(Vec::new());
+
+        if let Err(err) = result {
+            let _ = event_bus.send(PeerManagerEvent {
+                peer: None,
+                event: PeerEvent::RoomLeft(address.clone(), err),
+            });
+        }
+        thread::sleep(RECONNECT_INTERVAL);
+    });
+
+    RoomDiscovery {
+        attendants: attendants_rx,
+        present: Vec::new(),
+        network,
+        queued: VecDeque::new(),
+        next_announce: Instant::now() + ANNOUNCE_INTERVAL,
+    }
+}
+
+/// Speaks muxrpc with a room until the connection is lost
+fn run(
+    address: &str,
+    handshaker: &Handshaker,
+    event_bus: &mpsc::Sender<PeerManagerEvent>,
+    public_key: PublicKey,
+    attendants: &mpsc::Sender<Vec<PeerAddr>>,
+) -> Result<(), RoomError> {
+    let room = PeerAddr::lookup(address).context(InvalidAddress)?;
+    let (stream, keys) = handshaker.room_handshake(&room).context(ConnectFailed)?;
+    let portal = room.public_key;
+
+    let mut box_writer = BoxWriter::new(
+        stream.try_clone().context(StreamCloneFailed)?,
+        keys.write_key,
+        keys.write_noncegen,
+    );
+    let mut reader = PacketReader::new(BoxReader::new(
+        stream.try_clone().context(StreamCloneFailed)?,
+        keys.read_key,
+        keys.read_noncegen,
+    ));
+
+    let (writer, packets) = mpsc::channel::<Packet>();
+    thread::spawn(move || {
+        while let Ok(packet) = packets.recv() {
+            if muxrpc::send(&mut box_writer, &packet).is_err() {
+                return;
+            }
+        }
+        let _ = muxrpc::send_goodbye(box_writer);
+    });
+
+    let handle = RoomHandle {
+        writer,
+        tunnels: Arc::new(Mutex::new(Tunnels {
+            next_req: 1,
+            streams: HashMap::new(),
+            calls: HashMap::new(),
+        })),
+        local_addr: if handshaker.punches_through(&room) {
+            stream.local_addr().ok()
+        } else {
+            None
+        },
+    };
+    // rooms before 2.0 only list clients that announce themselves
+    handle.request(&["tunnel", "announce"], "async", json!([]));
+    let attendants_req = handle.request(&["room", "attendants"], "source", json!([]));
+
+    handshaker.rooms().insert(room.feed_id(), handle.clone());
+    let _ = event_bus.send(PeerManagerEvent {
+        peer: Some(room),
+        event: PeerEvent::RoomJoined,
+    });
+
+    let mut present = Vec::new();
+    let result = loop {
+        let packet = match reader.recv() {
+            Ok(Some(packet)) => packet,
+            Ok(None) => break Err(RoomError::Closed {}),
+            Err(err) => break Err(err).context(ReadFailed),
+        };
+
+        if packet.req == -attendants_req {
+            if let Some(event) = packet.json() {
+                update_attendants(&mut present, &event);
+                let _ = attendants.send(
+                    present
+                        .iter()
+                        .filter(|attendant| **attendant != public_key)
+                        .map(|attendant| PeerAddr {
+                            protocol: Protocol::Tunnel(portal),
+                            socket_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+                            public_key: *attendant,
+                        })
+                        .collect(),
+                );
+            }
+            continue;
+        }
+
+        if handle.route(&packet) || packet.req < 0 || packet.end_or_error {
+            continue;
+        }
+        answer(&packet, &handle, handshaker, event_bus, portal);
+    };
+
+    handshaker.rooms().remove(&room.feed_id());
+    handle.close();
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+/// Applies a `room.attendants` event: the full list first, then who
+/// joined and left
+fn update_attendants(present: &mut Vec<PublicKey>, event: &Value) {
+    let id = event["id"].as_str().and_then(parse_feed_id);
+    match (event["type"].as_str(), id) {
+        (Some("state"), _) => {
+            *present = event["ids"]
+                .as_array()
+                .map(|ids| {
+                    ids.iter()
+                        .filter_map(|id| id.as_str().and_then(parse_feed_id))
+                        .collect()
+                })
+                .unwrap_or_default();
+        }
+        (Some("joined"), Some(id)) if !present.contains(&id) => present.push(id),
+        (Some("left"), Some(id)) => present.retain(|attendant| *attendant != id),
+        _ => {}
+    }
+}
+
+/// Answers a call from the room, handshaking with attendants that tunnel
+/// to us
+fn answer(
+    packet: &Packet,
+    handle: &RoomHandle,
+    handshaker: &Handshaker,
+    event_bus: &mpsc::Sender<PeerManagerEvent>,
+    portal: PublicKey,
+) {
+    let reply = match packet.call() {
+        Some((ref name, ref args)) if name == "tunnel.connect" && packet.stream => {
+            let origin = args.first().and_then(|arg| arg["origin"].as_str());
+            if origin.and_then(parse_feed_id).is_none() {
+                Packet::error(-packet.req, true, "tunnel.connect needs an origin")
+            } else {
+                // the handshake proves who the origin is, whatever the
+                // room claims
+                let tunnel = handle.tunnel(-packet.req);
+                let handshaker = handshaker.clone();
+                let event_bus = event_bus.clone();
+                thread::spawn(move || accept(tunnel, &handshaker, &event_bus, portal));
+                return;
+            }
+        }
+        // the room tells us where an attendant dialing us is, and where it
+        // sees us, which is what the attendant is to dial
+        Some((ref name, ref args)) if name == "punch.connect" && !packet.stream => {
+            let arg = args.first();
+            let endpoint = arg
+                .and_then(|arg| arg["endpoint"].as_str())
+                .and_then(|endpoint| endpoint.parse::<SocketAddr>().ok());
+            let observed = arg.and_then(|arg| arg["observed"].as_str());
+
+            match (handle.local_addr, endpoint, observed) {
+                (None, _, _) => Packet::error(-packet.req, false, "not punching through"),
+                (Some(local_addr), Some(endpoint), Some(observed)) => {
+                    let handshaker = handshaker.clone();
+                    let event_bus = event_bus.clone();
+                    // the attendant tunnels to us if this fails
+                    thread::spawn(move || {
+                        if let Ok(stream) = punch::connect(local_addr, endpoint) {
+                            accept(stream, &handshaker, &event_bus, portal);
+                        }
+                    });
+                    Packet::value(-packet.req, false, &json!({ "endpoint": observed }))
+                }
+                _ => Packet::error(-packet.req, false, "punch.connect needs both endpoints"),
+            }
+        }
+        // rooms before 2.0 check their clients are still there
+        Some((ref name, _)) if name == "tunnel.ping" => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_millis() as u64)
+                .unwrap_or(0);
+            Packet::value(-packet.req, false, &json!(now))
+        }
+        _ => Packet::error(-packet.req, packet.stream, "method not supported"),
+    };
+    let _ = handle.writer.send(reply);
+}
+
+/// Handshakes with an attendant that tunnelled or punched through to us
+fn accept<S: Transport>(
+    stream: S,
+    handshaker: &Handshaker,
+    event_bus: &mpsc::Sender<PeerManagerEvent>,
+    portal: PublicKey,
+) {
+    let event = match handshaker.tunnel_handshake(stream, portal) {
+        Ok(peer_connection) => PeerManagerEvent {
+            peer: Some(peer_connection.peer),
+            event: PeerEvent::HandshakeSuccessful(Box::new(peer_connection)),
+        },
+        Err(err) => PeerManagerEvent {
+            peer: None,
+            event: PeerEvent::HandshakeFailed(err),
+        },
+    };
+    let _ = event_bus.send(event);
+}
+
+/// Lists the attendants of a room, announced every `ANNOUNCE_INTERVAL`
+/// like beacons so they expire once they leave
+pub struct RoomDiscovery {
+    attendants: mpsc::Receiver<Vec<PeerAddr>>,
+    present: Vec<PeerAddr>,
+    /// Attendants are on our first network
+    network: String,
+    queued: VecDeque<PeerAnnouncement>,
+    next_announce: Instant,
+}
+
+impl RoomDiscovery {
+    fn announcement(&self, peer: PeerAddr) -> PeerAnnouncement {
+        PeerAnnouncement {
+            peer,
+            network: self.network.clone(),
+            kind: AnnouncementKind::Room,
+            // the room checked their keys in its handshakes with them, and
+            // the tunnelled handshake checks them again
+            verified: true,
+        }
+    }
+}
+
+impl Discovery for RoomDiscovery {
+    fn recv(&mut self) -> Result<PeerAnnouncement, DiscoveryServiceError> {
+        loop {
+            if let Some(announcement) = self.queued.pop_front() {
+                return Ok(announcement);
+            }
+
+            let now = Instant::now();
+            if now >= self.next_announce {
+                let announcements = self
+                    .present
+                    .iter()
+                    .map(|peer| self.announcement(*peer))
+                    .collect::<Vec<_>>();
+                self.queued.extend(announcements);
+                self.next_announce = now + ANNOUNCE_INTERVAL;
+                continue;
+            }
+
+            match self.attendants.recv_timeout(self.next_announce - now) {
+                Ok(attendants) => {
+                    let joined = attendants
+                        .iter()
+                        .filter(|peer| {
+                            !self
+                                .present
+                                .iter()
+                                .any(|present| present.public_key == peer.public_key)
+                        })
+                        .map(|peer| self.announcement(*peer))
+                        .collect::<Vec<_>>();
+                    self.queued.extend(joined);
+                    self.present = attendants;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                // the room thread never stops, but a busy loop would be
+                // worse than a quiet one
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.present.clear();
+                    thread::sleep(self.next_announce - now);
+                }
+            }
+        }
+    }
+}
@@ -3,10 +3,9 @@ use ssb_crypto::{PublicKey, SecretKey, generate_longterm_keypair};
 use ssb_keyfile::load_keys_from_path;
 use std::path::PathBuf;
 
-const DEFAULT_SSB_DIR: &'static str = ".ssb";
+const DEFAULT_SSB_DIR: &str = ".ssb";
 
 pub struct SsbConfig {
-    config_dir: PathBuf,
     public_key: PublicKey,
     secret_key: SecretKey,
 }
@@ -27,7 +26,6 @@ impl SsbConfig {
         let (public_key, secret_key) = load_or_generate_keys(keyfile);
 
         SsbConfig {
-            config_dir,
             public_key,
             secret_key,
         }
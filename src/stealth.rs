@@ -0,0 +1,191 @@
+use crate::discovery::parse_feed_id;
+use sodiumoxide::crypto::pwhash::argon2id13;
+use sodiumoxide::crypto::{box_, secretbox};
+use ssb_crypto::{PublicKey, SecretKey};
+
+/// Stealth beacons are `stealth:` followed by the base64 nonce and
+/// ciphertext of a regular, signed announcement
+pub const STEALTH_PREFIX: &str = "stealth:";
+
+/// Every instance derives the same key from a passphrase, so the salt is
+/// fixed. It still keeps precomputed tables for other apps out of use.
+const SECRET_SALT: argon2id13::Salt = argon2id13::Salt(*b"scuttle-chat-lan");
+
+/// Keys for beacons only friends can read: secretboxes under shared
+/// secrets, and boxes between our key and each contact's
+#[derive(Clone)]
+pub struct StealthKeys {
+    secrets: Vec<secretbox::Key>,
+    /// Our key and the contacts' keys, converted to curve25519
+    secret_key: Option<box_::SecretKey>,
+    contacts: Vec<box_::PublicKey>,
+}
+
+impl StealthKeys {
+    /// Contacts that aren't valid feed ids are skipped, the config checks
+    /// them when it's loaded
+    pub fn new(secret_key: &SecretKey, secrets: &[String], contacts: &[String]) -> StealthKeys {
+        StealthKeys {
+            secrets: secrets
+                .iter()
+                .filter_map(|secret| secret_key_from(secret))
+                .collect(),
+            secret_key: curve25519_sk(secret_key),
+            contacts: contacts
+                .iter()
+                .filter_map(|feed_id| parse_feed_id(feed_id))
+                .filter_map(|public_key| curve25519_pk(&public_key))
+                .collect(),
+        }
+    }
+
+    /// One stealth beacon per shared secret and contact
+    pub fn seal(&self, announcement: &str) -> Vec<String> {
+        let plaintext = announcement.as_bytes();
+        let mut beacons = Vec::new();
+
+        for key in self.secrets.iter() {
+            let nonce = secretbox::gen_nonce();
+            let ciphertext = secretbox::seal(plaintext, &nonce, key);
+            beacons.push(beacon(&nonce.0, &ciphertext));
+        }
+        if let Some(secret_key) = &self.secret_key {
+            for contact in self.contacts.iter() {
+                let nonce = box_::gen_nonce();
+                let ciphertext = box_::seal(plaintext, &nonce, contact, secret_key);
+                beacons.push(beacon(&nonce.0, &ciphertext));
+            }
+        }
+
+        beacons
+    }
+
+    /// The announcement inside a stealth beacon, if any of our keys opens
+    /// it
+    pub fn open(&self, beacon: &str) -> Option<String> {
+        if !beacon.starts_with(STEALTH_PREFIX) {
+            return None;
+        }
+        let sealed = base64::decode(&beacon[STEALTH_PREFIX.len()..]).ok()?;
+        if sealed.len() < secretbox::NONCEBYTES {
+            return None;
+        }
+        // both nonces are 24 bytes
+        let (nonce, ciphertext) = sealed.split_at(secretbox::NONCEBYTES);
+
+        let plaintext = self
+            .secrets
+            .iter()
+            .find_map(|key| {
+                let nonce = secretbox::Nonce::from_slice(nonce)?;
+                secretbox::open(ciphertext, &nonce, key).ok()
+            })
+            .or_else(|| {
+                let secret_key = self.secret_key.as_ref()?;
+                let nonce = box_::Nonce::from_slice(nonce)?;
+                self.contacts
+                    .iter()
+                    .find_map(|contact| box_::open(ciphertext, &nonce, contact, secret_key).ok())
+            })?;
+
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+/// Stretches a passphrase into a key, so a sniffed beacon can't be used
+/// to cheaply guess short passphrases
+fn secret_key_from(secret: &str) -> Option<secretbox::Key> {
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    argon2id13::derive_key(
+        &mut key.0,
+        secret.as_bytes(),
+        &SECRET_SALT,
+        argon2id13::OPSLIMIT_INTERACTIVE,
+        argon2id13::MEMLIMIT_INTERACTIVE,
+    )
+    .ok()?;
+    Some(key)
+}
+
+/// sodiumoxide 0.2.5, which ssb-handshake needs, doesn't wrap libsodium's
+/// key conversions
+fn curve25519_sk(secret_key: &SecretKey) -> Option<box_::SecretKey> {
+    let mut curve25519 = [0; box_::SECRETKEYBYTES];
+    let result = unsafe {
+        libsodium_sys::crypto_sign_ed25519_sk_to_curve25519(
+            curve25519.as_mut_ptr(),
+            secret_key.0.as_ptr(),
+        )
+    };
+    Some(box_::SecretKey(curve25519)).filter(|_| result == 0)
+}
+
+fn curve25519_pk(public_key: &PublicKey) -> Option<box_::PublicKey> {
+    let mut curve25519 = [0; box_::PUBLICKEYBYTES];
+    let result = unsafe {
+        libsodium_sys::crypto_sign_ed25519_pk_to_curve25519(
+            curve25519.as_mut_ptr(),
+            public_key.0.as_ptr(),
+        )
+    };
+    Some(box_::PublicKey(curve25519)).filter(|_| result == 0)
+}
+
+fn beacon(nonce: &[u8], ciphertext: &[u8]) -> String {
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(ciphertext);
+    format!("{}{}", STEALTH_PREFIX, base64::encode(&sealed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sodiumoxide::crypto::sign;
+
+    const ANNOUNCEMENT: &str = "net:10.0.0.1:8008~shs:key";
+
+    fn keypair() -> (PublicKey, SecretKey) {
+        let (pk, sk) = sign::gen_keypair();
+        (
+            PublicKey::from_slice(&pk.0).unwrap(),
+            SecretKey::from_slice(&sk.0).unwrap(),
+        )
+    }
+
+    fn feed_id(public_key: &PublicKey) -> String {
+        format!("@{}.ed25519", base64::encode(&public_key.0))
+    }
+
+    #[test]
+    fn friends_with_the_same_secret_open_beacons() {
+        let (_, our_key) = keypair();
+        let (_, their_key) = keypair();
+        let secrets = ["correct horse".to_string()];
+        let ours = StealthKeys::new(&our_key, &secrets, &[]);
+        let theirs = StealthKeys::new(&their_key, &secrets, &[]);
+
+        let beacons = ours.seal(ANNOUNCEMENT);
+        assert_eq!(beacons.len(), 1);
+        assert_eq!(theirs.open(&beacons[0]).as_deref(), Some(ANNOUNCEMENT));
+
+        let others = StealthKeys::new(&their_key, &["battery staple".to_string()], &[]);
+        assert_eq!(others.open(&beacons[0]), None);
+    }
+
+    #[test]
+    fn contacts_open_beacons_sealed_for_them() {
+        let (our_pk, our_sk) = keypair();
+        let (their_pk, their_sk) = keypair();
+        let (_, stranger_sk) = keypair();
+        let ours = StealthKeys::new(&our_sk, &[], &[feed_id(&their_pk)]);
+        let theirs = StealthKeys::new(&their_sk, &[], &[feed_id(&our_pk)]);
+
+        let beacons = ours.seal(ANNOUNCEMENT);
+        assert_eq!(beacons.len(), 1);
+        assert_eq!(theirs.open(&beacons[0]).as_deref(), Some(ANNOUNCEMENT));
+
+        let stranger = StealthKeys::new(&stranger_sk, &[], &[feed_id(&our_pk)]);
+        assert_eq!(stranger.open(&beacons[0]), None);
+        assert_eq!(theirs.open(ANNOUNCEMENT), None);
+    }
+}
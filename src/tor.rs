@@ -0,0 +1,201 @@
+use crate::config;
+use crate::discovery::{OnionAddr, PeerAddr, Protocol};
+use snafu::ResultExt;
+use ssb_crypto::PublicKey;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const ONION_KEY_FILE: &str = "onion_key";
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Snafu, Debug)]
+pub enum TorError {
+    #[snafu(display("Failed to connect to Tor control port {}: {}", addr, source))]
+    ConnectFailed { addr: SocketAddr, source: io::Error },
+    #[snafu(display("Lost Tor control connection: {}", source))]
+    ControlIo { source: io::Error },
+    #[snafu(display("Tor rejected {}: {}", command, reply))]
+    Rejected { command: String, reply: String },
+    #[snafu(display("Failed to read Tor auth cookie {}: {}", path.display(), source))]
+    CookieReadFailed { path: PathBuf, source: io::Error },
+    #[snafu(display("Tor didn't name the onion service it created"))]
+    MissingServiceId {},
+    #[snafu(display("Failed to save onion service key {}: {}", path.display(), source))]
+    KeyWriteFailed { path: PathBuf, source: io::Error },
+}
+
+/// An onion service forwarding to our listener, Tor removes it once the
+/// control connection is dropped
+pub struct OnionService {
+    pub onion: OnionAddr,
+    pub port: u16,
+    _control: TcpStream,
+}
+
+impl OnionService {
+    pub fn peer_addr(&self, public_key: PublicKey) -> PeerAddr {
+        PeerAddr {
+            protocol: Protocol::Onion(self.onion),
+            socket_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), self.port),
+            public_key,
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/scuttle-chat/onion_key`, so the onion address stays
+/// the same across restarts
+pub fn default_key_path() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join(ONION_KEY_FILE))
+}
+
+/// A control connection speaking the line based Tor control protocol
+struct Control {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Control {
+    /// Sends a command and returns the lines of a 250 reply, without the
+    /// status code
+    fn command(&mut self, command: &str) -> Result<Vec<String>, TorError> {
+        write!(self.writer, "{}\r\n", command).context(ControlIo)?;
+
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).context(ControlIo)? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof)).context(ControlIo);
+            }
+            let line = line.trim_end();
+            if line.len() < 4 {
+                continue;
+            }
+
+            let (status, rest) = line.split_at(3);
+            if status != "250" {
+                return Err(TorError::Rejected {
+                    // don't echo passwords and keys into the log
+                    command: command.split(' ').next().unwrap_or("").to_string(),
+                    reply: line.to_string(),
+                });
+            }
+            lines.push(rest[1..].to_string());
+
+            // "250-" continues the reply, "250 " ends it
+            if rest.starts_with(' ') {
+                return Ok(lines);
+            }
+        }
+    }
+
+    /// Authenticates with the password if given, else with the cookie
+    /// file Tor names, else without credentials
+    fn authenticate(&mut self, password: Option<&str>) -> Result<(), TorError> {
+        if let Some(password) = password {
+            let quoted = password.replace('\\', "\\\\").replace('"', "\\\"");
+            self.command(&format!("AUTHENTICATE \"{}\"", quoted))?;
+            return Ok(());
+        }
+
+        let info = self.command("PROTOCOLINFO 1")?;
+        let cookie_file = info
+            .iter()
+            .filter(|line| line.starts_with("AUTH "))
+            .filter(|line| line.contains("COOKIE"))
+            .filter_map(|line| line.split("COOKIEFILE=\"").nth(1))
+            .filter_map(|rest| rest.split('"').next())
+            .next()
+            .map(PathBuf::from);
+
+        match cookie_file {
+            Some(path) => {
+                let cookie = fs::read(&path).context(CookieReadFailed { path })?;
+                let hex = cookie
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>();
+                self.command(&format!("AUTHENTICATE {}", hex))?;
+            }
+            None => {
+                self.command("AUTHENTICATE")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Publishes `port` on localhost as an onion service on the same port,
+/// reusing the key at `key_path` or saving a new one there.
+pub fn publish(
+    control_addr: SocketAddr,
+    password: Option<&str>,
+    key_path: Option<PathBuf>,
+    port: u16,
+) -> Result<OnionService, TorError> {
+    let stream = TcpStream::connect_timeout(&control_addr, CONTROL_TIMEOUT)
+        .context(ConnectFailed { addr: control_addr })?;
+    stream
+        .set_read_timeout(Some(CONTROL_TIMEOUT))
+        .context(ControlIo)?;
+    let mut control = Control {
+        reader: BufReader::new(stream.try_clone().context(ControlIo)?),
+        writer: stream,
+    };
+
+    control.authenticate(password)?;
+
+    let saved_key = key_path
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty());
+    let key = saved_key
+        .clone()
+        .unwrap_or_else(|| "NEW:ED25519-V3".to_string());
+
+    let reply = control.command(&format!(
+        "ADD_ONION {} Port={},127.0.0.1:{}",
+        key, port, port
+    ))?;
+
+    let onion = reply
+        .iter()
+        .filter_map(|line| line.strip_prefix("ServiceID="))
+        .filter_map(|service_id| OnionAddr::parse(&format!("{}.onion", service_id)))
+        .next()
+        .ok_or(TorError::MissingServiceId {})?;
+
+    // Tor only hands out the key of services it generated
+    let new_key = reply
+        .iter()
+        .filter_map(|line| line.strip_prefix("PrivateKey="))
+        .next();
+    if let (Some(path), Some(new_key), None) = (key_path, new_key, saved_key) {
+        save_key(&path, new_key)?;
+    }
+
+    Ok(OnionService {
+        onion,
+        port,
+        _control: control.writer,
+    })
+}
+
+/// The key is as good as our onion address, so only we can read it
+fn save_key(path: &Path, key: &str) -> Result<(), TorError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(KeyWriteFailed { path })?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", key))
+        .context(KeyWriteFailed { path })
+}
@@ -1,42 +1,51 @@
 use crate::app::{App, AppMode, UiStyles};
 use crate::chat::{ChatMsg, ChatSender};
+use crate::config::key_name;
+use crate::peers::PeerSource;
 use std::io;
 use tui::backend::Backend;
-use tui::layout::{Constraint, Corner, Direction, Layout, Margin, ScrollMode};
+use tui::layout::{Constraint, Direction, Layout, Rect, ScrollMode};
 use tui::style::{Color, Modifier, Style};
-use tui::widgets::{Block, Borders, List, Paragraph, SelectableList, Text, Widget};
+use tui::widgets::{Block, Borders, Paragraph, SelectableList, Text, Widget};
 use tui::Terminal;
 
 pub fn draw<'a, B: Backend>(terminal: &mut Terminal<B>, app: &App<'a>) -> Result<(), io::Error> {
-    terminal.draw(|mut f| match app.mode {
-        AppMode::Debug => {
-            let area = Layout::default()
-                .direction(Direction::Horizontal)
-                .margin(5)
-                .constraints([Constraint::Min(1)].as_ref())
-                .split(f.size());
-
-            draw_debug_window(&mut f, &app, area[0]);
-        }
-        _ => {
-            let panes = Layout::default()
-                .direction(Direction::Horizontal)
-                .constraints([Constraint::Percentage(45), Constraint::Percentage(55)].as_ref())
-                .split(f.size());
+    terminal.draw(|mut f| {
+        match app.mode {
+            AppMode::Debug => {
+                let area = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .margin(5)
+                    .constraints([Constraint::Min(1)].as_ref())
+                    .split(f.size());
+
+                draw_debug_window(&mut f, app, area[0]);
+            }
+            _ => {
+                let panes = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(45), Constraint::Percentage(55)].as_ref())
+                    .split(f.size());
 
-            draw_status_pane(&mut f, &app, panes[0]);
+                draw_status_pane(&mut f, app, panes[0]);
 
-            let chunks = Layout::default()
-                .direction(Direction::Vertical)
-                .constraints([Constraint::Min(1), Constraint::Length(5)].as_ref())
-                .split(panes[1]);
+                let chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Min(1), Constraint::Length(5)].as_ref())
+                    .split(panes[1]);
 
-            match app.selected {
-                None => draw_welcome_pane(&mut f, &app, chunks[0]),
-                Some(_) => draw_chat_pane(&mut f, &app, chunks[0]),
-            };
+                match app.peers.selected() {
+                    None => draw_welcome_pane(&mut f, app, chunks[0]),
+                    Some(_) => draw_chat_pane(&mut f, app, chunks[0]),
+                };
 
-            draw_input_area(&mut f, &app, chunks[1]);
+                draw_input_area(&mut f, app, chunks[1]);
+            }
+        }
+
+        if !app.pending_admissions.is_empty() {
+            let screen = f.size();
+            draw_admission_prompt(&mut f, app, screen);
         }
     })?;
 
@@ -51,26 +60,147 @@ fn draw_status_pane<'a, B: Backend>(
     let style = Style::default();
 
     let block_style = match app.mode {
-        AppMode::Normal => app.ui_styles.highlighted_block_style,
+        AppMode::Normal if !app.filtering && app.command.is_none() => {
+            app.ui_styles.highlighted_block_style
+        }
         _ => app.ui_styles.normal_block_style,
     };
 
+    // the filter prompt stays visible while a filter narrows the list,
+    // the command prompt takes its place while it's open
+    let area = if app.command.is_some() || app.filtering || !app.peers.filter().is_empty() {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(3)].as_ref())
+            .split(area);
+        match &app.command {
+            Some(command) => draw_command_prompt(f, app, command, chunks[1]),
+            None => draw_filter_prompt(f, app, chunks[1]),
+        }
+        chunks[0]
+    } else {
+        area
+    };
+
+    // only worth labelling peers with their network when there's a choice
+    let show_network = app.config.network.networks.len() > 1;
+    let peer_labels = app
+        .peers
+        .ordered()
+        .into_iter()
+        .map(|feed_id| {
+            let mut label = app.peers.display_name(feed_id);
+            if app.peers.is_favourite(feed_id) {
+                label.insert_str(0, "★ ");
+            }
+            if let Some(peer) = app.peers.get(feed_id) {
+                if show_network {
+                    label.push_str(&format!(" [{}]", peer.network));
+                }
+                match peer.source {
+                    PeerSource::Inbound => label.push_str(" (connected inbound)"),
+                    PeerSource::Manual => label.push_str(" (manual)"),
+                    PeerSource::Bootstrap => label.push_str(" (bootstrap)"),
+                    PeerSource::Stealth => label.push_str(" (stealth)"),
+                    PeerSource::Room => label.push_str(" (room)"),
+                    PeerSource::Exchange => {
+                        if let Some(via) = &peer.via {
+                            let via = match app.peers.alias(via) {
+                                Some(alias) => alias.to_string(),
+                                None => short_feed_id(via),
+                            };
+                            label.push_str(&format!(" (via {})", via));
+                        }
+                    }
+                    PeerSource::Ssb if !peer.chat_capable => {
+                        label.push_str(" (SSB peer, chat capability unknown)")
+                    }
+                    _ => {}
+                }
+                if !peer.verified {
+                    label.push_str(" (unverified)");
+                }
+                if !peer.online {
+                    label.push_str(" (offline)");
+                }
+                if peer.unread > 0 {
+                    label.push_str(&format!(" ({} unread)", peer.unread));
+                }
+            }
+            label
+        })
+        .collect::<Vec<String>>();
+
+    // offline peers are listed after the online ones
+    let offline = app.peers.iter().filter(|(_, peer)| !peer.online).count();
+    let mut title = match offline {
+        0 => "Available Peers".to_string(),
+        offline => format!("Available Peers ({} offline)", offline),
+    };
+    if !app.peers.filter().is_empty() {
+        title.push_str(&format!(" – {} matching", peer_labels.len()));
+    }
+
     SelectableList::default()
         .block(
             Block::default()
                 .borders(Borders::ALL)
-                .title("Available Peers")
+                .title(&title)
                 .border_style(block_style)
                 .title_style(block_style),
         )
-        .items(&app.peer_list())
-        .select(app.selected)
+        .items(&peer_labels)
+        .select(app.peers.selected_index())
         .style(style)
         .highlight_style(style.fg(Color::LightGreen).modifier(Modifier::BOLD))
         .highlight_symbol(">")
         .render(f, area);
 }
 
+fn draw_filter_prompt<'a, B: Backend>(
+    f: &mut tui::terminal::Frame<B>,
+    app: &App<'a>,
+    area: tui::layout::Rect,
+) {
+    let (block_style, text_style) = if app.filtering {
+        (app.ui_styles.highlighted_block_style, Style::default())
+    } else {
+        (
+            app.ui_styles.hidden_block_style,
+            Style::default().fg(Color::DarkGray),
+        )
+    };
+
+    Paragraph::new([Text::styled(format!("/{}", app.peers.filter()), text_style)].iter())
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Filter")
+                .border_style(block_style)
+                .title_style(block_style),
+        )
+        .render(f, area);
+}
+
+fn draw_command_prompt<'a, B: Backend>(
+    f: &mut tui::terminal::Frame<B>,
+    app: &App<'a>,
+    command: &str,
+    area: tui::layout::Rect,
+) {
+    let block_style = app.ui_styles.highlighted_block_style;
+
+    Paragraph::new([Text::raw(format!(":{}", command))].iter())
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Command")
+                .border_style(block_style)
+                .title_style(block_style),
+        )
+        .render(f, area);
+}
+
 fn draw_debug_window<'a, B: Backend>(
     f: &mut tui::terminal::Frame<B>,
     app: &App<'a>,
@@ -109,8 +239,28 @@ fn draw_welcome_pane<'a, B: Backend>(
     app: &App<'a>,
     area: tui::layout::Rect,
 ) {
-    let welcome_text = vec![Text::styled(
-        "
+    let keys = &app.config.keybindings;
+    let key_help = [
+        (key_name(keys.up), "Select up"),
+        (key_name(keys.down), "Select down"),
+        (key_name(keys.select), "Start chat with selected peer"),
+        (key_name(keys.filter), "Filter peers by alias or feed id"),
+        (
+            key_name(keys.command),
+            "Run a command, e.g. connect <address>",
+        ),
+        (key_name(keys.back), "Return to main menu"),
+        ("h".to_string(), "Help (not yet implemented)"),
+        (key_name(keys.debug), "View debug window"),
+        (key_name(keys.quit), "Quit"),
+    ]
+    .iter()
+    .map(|(key, action)| format!("  {:<9}: {}\n", format!("<{}>", key), action))
+    .collect::<String>();
+
+    let welcome_text = [Text::styled(
+        format!(
+            "
 
   ███████╗ ██████╗██╗   ██╗████████╗████████╗██╗     ███████╗ 
   ██╔════╝██╔════╝██║   ██║╚══██╔══╝╚══██╔══╝██║     ██╔════╝ 
@@ -125,15 +275,10 @@ fn draw_welcome_pane<'a, B: Backend>(
                     ╚██████╗██║  ██║██║  ██║   ██║   
                      ╚═════╝╚═╝  ╚═╝╚═╝  ╚═╝   ╚═╝   
 
-  <k>      : Select up
-  <j>      : Select down
-  <RETURN> : Start chat with selected peer
-  <ESC>    : Return to main menu
-  <h>      : Help (not yet implemented)
-  <d>      : View debug window
-  <q>      : Quit
-
+{}
 ",
+            key_help
+        ),
         app.ui_styles.info_style,
     )];
 
@@ -153,13 +298,12 @@ fn draw_chat_pane<'a, B: Backend>(
     app: &App<'a>,
     area: tui::layout::Rect,
 ) {
-    let selected_peer = app
-        .selected
-        .and_then(|idx| app.available_peers.keys().nth(idx));
-
     let chat_title = format!(
         "Chat ({})",
-        selected_peer.unwrap_or(&"No peer selected".to_string())
+        app.peers
+            .selected()
+            .map(|feed_id| app.peers.display_name(feed_id))
+            .unwrap_or("No peer selected".to_string())
     );
 
     let scroll_offset = app
@@ -238,3 +382,72 @@ fn draw_input_area<'a, B: Backend>(
         .scroll(0)
         .render(f, area);
 }
+
+/// Shortens "@<base64>.ed25519" feed ids for places too narrow for them
+fn short_feed_id(feed_id: &str) -> String {
+    match feed_id.get(..8) {
+        Some(prefix) if feed_id.len() > 8 => format!("{}…", prefix),
+        _ => feed_id.to_string(),
+    }
+}
+
+fn draw_admission_prompt<'a, B: Backend>(
+    f: &mut tui::terminal::Frame<B>,
+    app: &App<'a>,
+    screen: Rect,
+) {
+    let peer_connection = match app.pending_admissions.front() {
+        Some(peer_connection) => peer_connection,
+        None => return,
+    };
+
+    let width = screen.width.min(64);
+    let height = screen.height.min(8);
+    let area = Rect::new(
+        screen.x + (screen.width - width) / 2,
+        screen.y + (screen.height - height) / 2,
+        width,
+        height,
+    );
+
+    let feed_id = peer_connection.peer.feed_id();
+    let requester = match app.peers.alias(&feed_id) {
+        Some(alias) => format!("{} ({})", short_feed_id(&feed_id), alias),
+        None => short_feed_id(&feed_id),
+    };
+
+    let mut lines = vec![
+        format!(
+            "{} wants to chat ({} network)",
+            requester, peer_connection.network
+        ),
+        "".to_string(),
+        "<y> accept  <n> reject  <a> always allow  <b> block".to_string(),
+    ];
+    if app.pending_admissions.len() > 1 {
+        lines.push(format!("{} more waiting", app.pending_admissions.len() - 1));
+    }
+
+    // tui doesn't clear what's underneath a widget, so pad every line of
+    // the prompt out to its full size
+    let inner_width = width.saturating_sub(2) as usize;
+    let prompt_text = (0..height.saturating_sub(2) as usize)
+        .map(|i| {
+            let line = lines.get(i).map(String::as_str).unwrap_or("");
+            Text::styled(
+                format!(" {:<width$}\n", line, width = inner_width.saturating_sub(1)),
+                app.ui_styles.info_style,
+            )
+        })
+        .collect::<Vec<Text>>();
+
+    Paragraph::new(prompt_text.iter())
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Chat Request")
+                .border_style(app.ui_styles.highlighted_block_style)
+                .title_style(app.ui_styles.highlighted_block_style),
+        )
+        .render(f, area);
+}
@@ -0,0 +1,203 @@
+use crate::peer_connection::{Transport, HANDSHAKE_TIMEOUT};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tungstenite::error::ProtocolError;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+/// The TCP stream under a WebSocket. Its clones read on their own but
+/// take turns writing, and write everything tungstenite hands them in one
+/// go, so the pongs of the WebSocket reading never split the frames of
+/// the one writing.
+struct WsSocket {
+    stream: TcpStream,
+    writer: Arc<Mutex<TcpStream>>,
+}
+
+impl WsSocket {
+    fn new(stream: TcpStream) -> io::Result<WsSocket> {
+        let writer = stream.try_clone()?;
+        Ok(WsSocket {
+            stream,
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    fn try_clone(&self) -> io::Result<WsSocket> {
+        Ok(WsSocket {
+            stream: self.stream.try_clone()?,
+            writer: self.writer.clone(),
+        })
+    }
+}
+
+impl Read for WsSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for WsSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.lock().unwrap().write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+/// Byte stream carried in binary WebSocket frames, for peers behind HTTP
+/// only reverse proxies. Each write goes out as one frame, frames are read
+/// back to back.
+pub struct WsStream {
+    ws: WebSocket<WsSocket>,
+    role: Role,
+    /// Rest of the last frame read
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+fn io_error(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        err => io::Error::other(err.to_string()),
+    }
+}
+
+fn set_upgrade_timeouts(stream: &TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))
+}
+
+/// Upgrades a freshly dialed stream to a WebSocket
+pub fn connect(stream: TcpStream, host: &str) -> io::Result<WsStream> {
+    set_upgrade_timeouts(&stream)?;
+    let (ws, _) = tungstenite::client(format!("ws://{}/", host), WsSocket::new(stream)?)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    Ok(WsStream {
+        ws,
+        role: Role::Client,
+        buf: Vec::new(),
+        pos: 0,
+    })
+}
+
+/// Answers the HTTP upgrade of an incoming WebSocket, whatever its path
+pub fn accept(stream: TcpStream) -> io::Result<WsStream> {
+    set_upgrade_timeouts(&stream)?;
+    let ws =
+        tungstenite::accept(WsSocket::new(stream)?).map_err(|e| io::Error::other(e.to_string()))?;
+
+    Ok(WsStream {
+        ws,
+        role: Role::Server,
+        buf: Vec::new(),
+        pos: 0,
+    })
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            match self.ws.read() {
+                Ok(Message::Binary(data)) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                // a closed WebSocket is the end of the stream, whether or
+                // not the peer said so before closing the socket
+                Ok(Message::Close(_))
+                | Err(tungstenite::Error::ConnectionClosed)
+                | Err(tungstenite::Error::AlreadyClosed)
+                | Err(tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake)) => {
+                    return Ok(0)
+                }
+                // pings are answered by tungstenite
+                Ok(_) => {}
+                Err(err) => return Err(io_error(err)),
+            }
+        }
+
+        let read = (&self.buf[self.pos..]).read(buf)?;
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.ws
+            .send(Message::Binary(buf.to_vec()))
+            .map_err(io_error)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.ws.flush().map_err(io_error)
+    }
+}
+
+impl Transport for WsStream {
+    /// Clones are for writing only, frames already read and the rest of
+    /// the last one stay with the original
+    fn try_clone(&self) -> io::Result<WsStream> {
+        Ok(WsStream {
+            ws: WebSocket::from_raw_socket(self.ws.get_ref().try_clone()?, self.role, None),
+            role: self.role,
+            buf: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.ws.get_ref().stream.shutdown(Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.ws.get_ref().stream.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.ws.get_ref().stream.set_write_timeout(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn frames_round_trip_and_clones_write() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut ws = accept(stream).unwrap();
+            let mut hello = [0; 5];
+            ws.read_exact(&mut hello).unwrap();
+            ws.write_all(b"world").unwrap();
+            hello
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = connect(stream, &addr.to_string()).unwrap();
+        let mut writer = reader.try_clone().unwrap();
+        writer.write_all(b"hello").unwrap();
+
+        let mut world = [0; 5];
+        reader.read_exact(&mut world).unwrap();
+        assert_eq!(&world, b"world");
+        assert_eq!(&server.join().unwrap(), b"hello");
+
+        writer.shutdown().unwrap();
+        assert_eq!(reader.read(&mut world).unwrap(), 0);
+    }
+}